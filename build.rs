@@ -43,12 +43,15 @@ struct TrieNode {
 
 struct KeywordTrie {
     nodes: Vec<TrieNode>,
+    // The trie can't be walked back to names, so they are listed separately
+    names: Vec<String>,
 }
 
 impl KeywordTrie {
     fn new() -> Self {
         Self {
             nodes: vec![Default::default()],
+            names: Vec::new(),
         }
     }
 
@@ -86,6 +89,7 @@ impl KeywordTrie {
             i = self.nodes[i].children[index];
         }
         self.nodes[i].mapping = Some(val);
+        self.names.push(name.to_string());
     }
 
     // Output functions
@@ -101,6 +105,14 @@ impl KeywordTrie {
         }
     }
 
+    fn write_names(&self, f: &mut File) {
+        writeln!(f, "static KEYWORD_NAMES: &[&str] = &[").unwrap();
+        for name in &self.names {
+            writeln!(f, "    {:?},", name).unwrap();
+        }
+        writeln!(f, "];").unwrap();
+    }
+
     fn write_node(&self, i: usize, f: &mut File, seen: &mut HashSet<usize>) {
         assert!(seen.insert(i)); // We should never be writing the same node twice
 
@@ -118,11 +130,11 @@ impl KeywordTrie {
         .unwrap();
         for &child in &self.nodes[i].children {
             if child == 0 {
-                write!(f, "None,\n").unwrap();
+                writeln!(f, "None,").unwrap();
             } else {
-                write!(f, "Some(\n").unwrap();
+                writeln!(f, "Some(").unwrap();
                 self.write_node(child, f, seen);
-                write!(f, "),\n").unwrap();
+                writeln!(f, "),").unwrap();
             }
         }
         write!(f, "],\n}}\n").unwrap();
@@ -130,7 +142,7 @@ impl KeywordTrie {
 }
 
 fn extract_token_names(parser_file_name: &str) -> std::io::Result<()> {
-    println!("{}", format!("cargo:rerun-if-changed={}", parser_file_name));
+    println!("cargo:rerun-if-changed={}", parser_file_name);
     let out_dir = env::var("OUT_DIR").unwrap();
 
     let mut tok_names = File::create(Path::new(&out_dir).join("token_names.rs"))?;
@@ -172,7 +184,7 @@ fn extract_token_names(parser_file_name: &str) -> std::io::Result<()> {
                         Some(_) => {
                             let name = slice(&reg_locs.get(1).unwrap());
                             let mut val = slice(&reg_locs.get(2).unwrap()).to_string();
-                            if let Some(_) = reg_locs.get(3) {
+                            if reg_locs.get(3).is_some() {
                                 val.push_str("(_)");
                             }
 
@@ -214,7 +226,8 @@ static KEYWORDS: TrieNode =
         )
         .unwrap();
     keywords.write(&mut keywords_file);
-    keywords_file.write_all(b";\n").unwrap();
+    keywords_file.write_all(b";\n\n").unwrap();
+    keywords.write_names(&mut keywords_file);
 
     Ok(())
 }
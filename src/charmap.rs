@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+
+#[derive(Debug, Default, Clone)]
+struct CharmapNode {
+    children: HashMap<char, usize>,
+    value: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Charmap {
+    nodes: Vec<CharmapNode>,
+}
+
+impl Charmap {
+    pub fn new() -> Self {
+        Self {
+            nodes: vec![Default::default()],
+        }
+    }
+
+    pub fn add(&mut self, from: &str, to: Vec<u8>) {
+        let mut i = 0;
+        for c in from.chars() {
+            // If no child node there, allocate a new one
+            i = match self.nodes[i].children.get(&c) {
+                Some(&child) => child,
+                None => {
+                    let nb_nodes = self.nodes.len();
+                    self.nodes.push(Default::default());
+                    self.nodes[i].children.insert(c, nb_nodes);
+                    nb_nodes
+                }
+            };
+        }
+        self.nodes[i].value = Some(to);
+    }
+
+    // Returns the value of a character constant, which must be a single mapping or character
+    // Multi-byte mappings are read big-endian, and unmapped characters are their code point
+    pub fn translate_char(&self, input: &str) -> Option<i32> {
+        let mut node = Some(0);
+        for c in input.chars() {
+            node = node.and_then(|i| self.nodes[i].children.get(&c).copied());
+        }
+        if let Some(value) = node.and_then(|i| self.nodes[i].value.as_ref()) {
+            return Some(
+                value
+                    .iter()
+                    .fold(0, |acc, &byte| acc << 8 | i32::from(byte)),
+            );
+        }
+
+        let mut chars = input.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c as i32),
+            _ => None,
+        }
+    }
+
+    // Translates a string, always picking the longest mapping available
+    // Characters that aren't mapped are output as-is (UTF-8)
+    pub fn translate(&self, input: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut rest = input;
+
+        while let Some(c) = rest.chars().next() {
+            let mut i = 0;
+            let mut longest = None;
+            for (ofs, ch) in rest.char_indices() {
+                match self.nodes[i].children.get(&ch) {
+                    Some(&child) => i = child,
+                    None => break,
+                }
+                if let Some(value) = &self.nodes[i].value {
+                    longest = Some((value, ofs + ch.len_utf8()));
+                }
+            }
+
+            match longest {
+                Some((value, len)) => {
+                    out.extend_from_slice(value);
+                    rest = &rest[len..];
+                }
+                None => {
+                    let mut buf = [0; 4];
+                    out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+        }
+        out
+    }
+}
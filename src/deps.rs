@@ -0,0 +1,27 @@
+use crate::Assembler;
+use std::io::{self, Write};
+
+// Escapes a path for use in a Makefile rule
+fn escape(path: &str) -> String {
+    path.replace('$', "$$")
+        .replace(' ', "\\ ")
+        .replace('#', "\\#")
+}
+
+impl Assembler<'_> {
+    pub(crate) fn add_dependency(&self, path: String) {
+        let mut deps = self.deps.borrow_mut();
+        if !deps.contains(&path) {
+            deps.push(path);
+        }
+    }
+
+    // Writes a Makefile rule listing all files `target` depends on
+    pub fn write_deps(&self, target: &str, mut out: impl Write) -> io::Result<()> {
+        write!(out, "{}:", escape(target))?;
+        for path in self.deps.borrow().iter() {
+            write!(out, " {}", escape(path))?;
+        }
+        writeln!(out)
+    }
+}
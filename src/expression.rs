@@ -1,16 +1,360 @@
+use crate::AssemblerError;
 use std::convert::TryFrom;
-use std::ops::{BitOr, Mul, Neg, Shl};
+use std::fmt::{self, Display, Formatter};
+use std::ops::{BitOr, Neg, Shl};
+use std::rc::Rc;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnOp {
+    Neg,
+    Cpl,
+    LogNot,
+    // Checks that an `ldh` address is in HRAM, and keeps its low byte
+    Hram,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Or,
+    And,
+    Xor,
+    LogAnd,
+    LogOr,
+    Eq,
+    Neq,
+    Gt,
+    Lt,
+    Gte,
+    Lte,
+    Shl,
+    Shr,
+}
+
+impl UnOp {
+    fn symbol(self) -> &'static str {
+        match self {
+            Self::Neg => "-",
+            Self::Cpl => "~",
+            Self::LogNot => "!",
+            Self::Hram => "",
+        }
+    }
+
+    fn apply(self, val: i32) -> i32 {
+        match self {
+            Self::Neg => val.wrapping_neg(),
+            Self::Cpl => !val,
+            Self::LogNot => (val == 0).into(),
+            Self::Hram => val & 0xff,
+        }
+    }
+}
+
+impl BinOp {
+    fn symbol(self) -> &'static str {
+        match self {
+            Self::Add => "+",
+            Self::Sub => "-",
+            Self::Mul => "*",
+            Self::Div => "/",
+            Self::Mod => "%",
+            Self::Or => "|",
+            Self::And => "&",
+            Self::Xor => "^",
+            Self::LogAnd => "&&",
+            Self::LogOr => "||",
+            Self::Eq => "==",
+            Self::Neq => "!=",
+            Self::Gt => ">",
+            Self::Lt => "<",
+            Self::Gte => ">=",
+            Self::Lte => "<=",
+            Self::Shl => "<<",
+            Self::Shr => ">>",
+        }
+    }
+
+    // Same tiers as the grammar, tightest-binding last
+    fn precedence(self) -> u8 {
+        match self {
+            Self::LogOr => 1,
+            Self::LogAnd => 2,
+            Self::Eq | Self::Neq | Self::Gt | Self::Lt | Self::Gte | Self::Lte => 3,
+            Self::Add | Self::Sub => 4,
+            Self::Or | Self::And | Self::Xor => 5,
+            Self::Shl | Self::Shr => 6,
+            Self::Mul | Self::Div | Self::Mod => 7,
+        }
+    }
+
+    fn apply(self, lhs: i32, rhs: i32) -> Result<i32, AssemblerError> {
+        Ok(match self {
+            Self::Add => lhs.wrapping_add(rhs),
+            Self::Sub => lhs.wrapping_sub(rhs),
+            Self::Mul => lhs.wrapping_mul(rhs),
+            Self::Div => {
+                if rhs == 0 {
+                    return Err(AssemblerError::DivByZero);
+                }
+                lhs.wrapping_div(rhs)
+            }
+            Self::Mod => {
+                if rhs == 0 {
+                    return Err(AssemblerError::DivByZero);
+                }
+                lhs.wrapping_rem(rhs)
+            }
+            Self::Or => lhs | rhs,
+            Self::And => lhs & rhs,
+            Self::Xor => lhs ^ rhs,
+            Self::LogAnd => (lhs != 0 && rhs != 0).into(),
+            Self::LogOr => (lhs != 0 || rhs != 0).into(),
+            Self::Eq => (lhs == rhs).into(),
+            Self::Neq => (lhs != rhs).into(),
+            Self::Gt => (lhs > rhs).into(),
+            Self::Lt => (lhs < rhs).into(),
+            Self::Gte => (lhs >= rhs).into(),
+            Self::Lte => (lhs <= rhs).into(),
+            Self::Shl => shift_left(lhs, rhs),
+            Self::Shr => shift_right(lhs, rhs),
+        })
+    }
+}
+
+// Like RGBDS, shifting by 32 or more shifts every bit out, and negative amounts shift the other way
+fn shift_left(val: i32, amount: i32) -> i32 {
+    match amount {
+        0..=31 => val << amount,
+        32.. => 0,
+        _ => shift_right(val, amount.saturating_neg()),
+    }
+}
+
+// Shifts are arithmetic, so negative values end up as -1 instead of 0
+fn shift_right(val: i32, amount: i32) -> i32 {
+    match amount {
+        0..=31 => val >> amount,
+        32.. => val >> 31,
+        _ => shift_left(val, amount.saturating_neg()),
+    }
+}
+
+// What a deferred expression's value is relative to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelocBase {
+    Symbol(Rc<String>),
+    SectStart(Rc<String>),
+}
+
+// How the linker will have to compute an expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchKind {
+    Absolute(i32),
+    // The base's address, plus a constant offset
+    Relative(RelocBase, i32),
+    // Anything else, which has to be evaluated as a whole
+    Opaque,
+}
+
+// Expressions are folded as they are built; whatever cannot be computed at assembly time
+// (e.g. labels in floating sections) is kept as a tree, and left to the linker
+#[derive(Debug, Clone)]
 pub enum Expression {
     Known(i32),
-    Unknown,
+    // A gfx constant, which `DB` emits as a whole row of pixels (two bytes)
+    Gfx(i32),
+    Symbol(Rc<String>),
+    // Properties of a section, by name
+    SectSize(Rc<String>),
+    SectStart(Rc<String>),
+    Unary(UnOp, Box<Expression>),
+    Binary(BinOp, Box<Expression>, Box<Expression>),
+}
+
+impl Expression {
+    // Anything computed from a gfx constant is just a number
+    pub(crate) fn plain(self) -> Self {
+        match self {
+            Self::Gfx(val) => Self::Known(val),
+            expr => expr,
+        }
+    }
+
+    pub fn unary(op: UnOp, expr: Self) -> Self {
+        match expr.plain() {
+            Self::Known(val) => Self::Known(op.apply(val)),
+            expr => Self::Unary(op, Box::new(expr)),
+        }
+    }
+
+    pub fn binary(op: BinOp, lhs: Self, rhs: Self) -> Result<Self, AssemblerError> {
+        match (lhs.plain(), rhs.plain()) {
+            (Self::Known(lhs), Self::Known(rhs)) => Ok(Self::Known(op.apply(lhs, rhs)?)),
+            (lhs, rhs) => Ok(Self::Binary(op, Box::new(lhs), Box::new(rhs))),
+        }
+    }
+
+    // Whether the linker will need the symbol to compute this
+    pub fn references(&self, name: &str) -> bool {
+        match self {
+            Self::Known(_) | Self::Gfx(_) | Self::SectSize(_) | Self::SectStart(_) => false,
+            Self::Symbol(sym) => sym.as_str() == name,
+            Self::Unary(_, expr) => expr.references(name),
+            Self::Binary(_, lhs, rhs) => lhs.references(name) || rhs.references(name),
+        }
+    }
+
+    pub fn classify(&self) -> PatchKind {
+        match self {
+            Self::Known(val) | Self::Gfx(val) => PatchKind::Absolute(*val),
+            Self::Symbol(name) => PatchKind::Relative(RelocBase::Symbol(Rc::clone(name)), 0),
+            Self::SectStart(name) => PatchKind::Relative(RelocBase::SectStart(Rc::clone(name)), 0),
+            Self::SectSize(_) => PatchKind::Opaque,
+            Self::Unary(op, expr) => match expr.classify() {
+                PatchKind::Absolute(val) => PatchKind::Absolute(op.apply(val)),
+                _ => PatchKind::Opaque,
+            },
+            Self::Binary(op, lhs, rhs) => match (op, lhs.classify(), rhs.classify()) {
+                (op, PatchKind::Absolute(lhs), PatchKind::Absolute(rhs)) => op
+                    .apply(lhs, rhs)
+                    .map_or(PatchKind::Opaque, PatchKind::Absolute),
+                (BinOp::Add, PatchKind::Relative(base, ofs), PatchKind::Absolute(val))
+                | (BinOp::Add, PatchKind::Absolute(val), PatchKind::Relative(base, ofs)) => {
+                    PatchKind::Relative(base, ofs.wrapping_add(val))
+                }
+                (BinOp::Sub, PatchKind::Relative(base, ofs), PatchKind::Absolute(val)) => {
+                    PatchKind::Relative(base, ofs.wrapping_sub(val))
+                }
+                _ => PatchKind::Opaque,
+            },
+        }
+    }
+
+    // === Range checks for emission; negative values stand for their two's complement ===
+
+    pub fn as_u8(&self) -> Result<u8, AssemblerError> {
+        let val = self.known()?;
+        if !(-128..=255).contains(&val) {
+            return Err(AssemblerError::ValueOutOfRange8(val));
+        }
+        Ok(val as u8)
+    }
+
+    pub fn as_i8(&self) -> Result<i8, AssemblerError> {
+        let val = self.known()?;
+        i8::try_from(val).map_err(|_| AssemblerError::ValueOutOfRange8(val))
+    }
+
+    pub fn as_u16(&self) -> Result<u16, AssemblerError> {
+        let val = self.known()?;
+        if !(-32768..=65535).contains(&val) {
+            return Err(AssemblerError::ValueOutOfRange16(val));
+        }
+        Ok(val as u16)
+    }
+
+    // `ldh` addresses may be written in full ($FF00-$FFFF) or as just their low byte
+    // Values only the linker can compute are checked by it
+    pub fn check_hram(self) -> Result<Self, AssemblerError> {
+        match self.plain() {
+            Self::Known(val @ 0xff00..=0xffff) => Ok(Self::Known(val & 0xff)),
+            Self::Known(val @ 0x00..=0xff) => Ok(Self::Known(val)),
+            Self::Known(val) => Err(AssemblerError::BadHramAddr(val)),
+            expr => Ok(Self::Unary(UnOp::Hram, Box::new(expr))),
+        }
+    }
+
+    // === Formatting, like RGBDS' interpolation ===
+
+    fn known(&self) -> Result<i32, AssemblerError> {
+        match self {
+            Self::Known(val) | Self::Gfx(val) => Ok(*val),
+            expr => Err(AssemblerError::ExprNotConstant(expr.to_string())),
+        }
+    }
+
+    pub fn to_hex(&self, width: usize) -> Result<String, AssemblerError> {
+        Ok(format_hex(self.known()?, width))
+    }
+
+    pub fn to_bin(&self, width: usize) -> Result<String, AssemblerError> {
+        Ok(format_bin(self.known()?, width))
+    }
+
+    pub fn to_oct(&self, width: usize) -> Result<String, AssemblerError> {
+        Ok(format_oct(self.known()?, width))
+    }
+
+    pub fn to_fixed(&self, precision: usize, fract_bits: u8) -> Result<String, AssemblerError> {
+        Ok(format_fixed(self.known()?, precision, fract_bits))
+    }
+}
+
+pub fn format_hex(val: i32, width: usize) -> String {
+    format!("{:0width$X}", val, width = width)
+}
+
+pub fn format_bin(val: i32, width: usize) -> String {
+    format!("{:0width$b}", val, width = width)
+}
+
+pub fn format_oct(val: i32, width: usize) -> String {
+    format!("{:0width$o}", val, width = width)
+}
+
+// The value is read as fixed-point, with `fract_bits` fractional bits
+pub fn format_fixed(val: i32, precision: usize, fract_bits: u8) -> String {
+    format!(
+        "{:.*}",
+        precision,
+        f64::from(val) / f64::from(1_u32 << fract_bits)
+    )
 }
 
 impl Expression {
-    pub fn check_hram(self) -> Self {
-        unimplemented!();
-        self
+    // Operators are left-associative, so only the right operand needs parens at the same tier
+    fn precedence(&self) -> u8 {
+        match self {
+            Self::Binary(op, ..) => op.precedence(),
+            Self::Known(val) | Self::Gfx(val) if *val < 0 => 8,
+            _ => 9,
+        }
+    }
+
+    fn fmt_operand(&self, fmt: &mut Formatter, min_precedence: u8) -> Result<(), fmt::Error> {
+        if self.precedence() < min_precedence {
+            write!(fmt, "({})", self)
+        } else {
+            write!(fmt, "{}", self)
+        }
+    }
+}
+
+// Renders the expression as it could be written in the source
+impl Display for Expression {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Self::Known(val) | Self::Gfx(val) => write!(fmt, "{}", val),
+            Self::Symbol(name) => write!(fmt, "{}", name),
+            Self::SectSize(name) => write!(fmt, "SIZEOF(\"{}\")", name),
+            Self::SectStart(name) => write!(fmt, "STARTOF(\"{}\")", name),
+            // The HRAM check is implied by `ldh`, it's not written out
+            Self::Unary(UnOp::Hram, expr) => write!(fmt, "{}", expr),
+            Self::Unary(op, expr) => {
+                write!(fmt, "{}", op.symbol())?;
+                expr.fmt_operand(fmt, 9)
+            }
+            Self::Binary(op, lhs, rhs) => {
+                lhs.fmt_operand(fmt, op.precedence())?;
+                write!(fmt, " {} ", op.symbol())?;
+                rhs.fmt_operand(fmt, op.precedence() + 1)
+            }
+        }
     }
 }
 
@@ -25,8 +369,8 @@ impl TryFrom<Expression> for i32 {
 
     fn try_from(expr: Expression) -> Result<Self, Self::Error> {
         match expr {
-            Expression::Known(val) => Ok(val),
-            Expression::Unknown => Err(Self::Error::ExprNotConstant),
+            Expression::Known(val) | Expression::Gfx(val) => Ok(val),
+            expr => Err(Self::Error::ExprNotConstant(expr.to_string())),
         }
     }
 }
@@ -34,20 +378,15 @@ impl TryFrom<Expression> for i32 {
 impl BitOr for Expression {
     type Output = Self;
     fn bitor(self, rhs: Self) -> Self {
-        match self {
-            Self::Known(val) => val | rhs,
-            Self::Unknown => unimplemented!(),
-        }
+        // Bitwise OR cannot fail
+        Self::binary(BinOp::Or, self, rhs).unwrap()
     }
 }
 
 impl BitOr<i32> for Expression {
     type Output = Self;
     fn bitor(self, rhs: i32) -> Self {
-        match self {
-            Self::Known(val) => Self::Known(val | rhs),
-            Self::Unknown => unimplemented!(),
-        }
+        self | Self::Known(rhs)
     }
 }
 
@@ -61,29 +400,21 @@ impl BitOr<Expression> for i32 {
 impl Neg for Expression {
     type Output = Self;
     fn neg(self) -> Self {
-        match self {
-            Self::Known(val) => Self::Known(-val),
-            Self::Unknown => unimplemented!(),
-        }
+        Self::unary(UnOp::Neg, self)
     }
 }
 
 impl Shl for Expression {
     type Output = Self;
     fn shl(self, rhs: Self) -> Self {
-        match rhs {
-            Self::Known(val) => self << val,
-            Self::Unknown => unimplemented!(),
-        }
+        // Shifting cannot fail
+        Self::binary(BinOp::Shl, self, rhs).unwrap()
     }
 }
 
 impl Shl<i32> for Expression {
     type Output = Self;
     fn shl(self, rhs: i32) -> Self {
-        match self {
-            Self::Known(lhs) => Self::Known(lhs << rhs),
-            Self::Unknown => unimplemented!(),
-        }
+        self << Self::Known(rhs)
     }
 }
@@ -1,10 +1,141 @@
 use crate::expression::Expression;
+use crate::{Assembler, AssemblerError};
+use std::convert::TryFrom;
 
 #[derive(Debug)]
 pub enum Instruction {
     NoArg(i32),
     Arg8(i32, Expression),
     Arg16(i32, Expression),
+    Jp(i32, Expression),
     Jr(i32, Expression),
+    SpOfs(i32, Expression),
     Rst(Expression),
 }
+
+// Operands of `ld` and `ldh`, using the same register numbering as the opcodes
+#[derive(Debug)]
+pub enum Operand {
+    Reg8(i32),
+    Reg16(i32),
+    Reg16Indirect(i32),
+    MemImm(Expression),
+    HighMem(Expression),
+    // `[c]`, optionally written as `[$ff00+c]`
+    HighC(Expression),
+    Imm(Expression),
+}
+
+const REG_HL_IND: i32 = 6;
+const REG_A: i32 = 7;
+const REG_HL: i32 = 2;
+const REG_SP: i32 = 3;
+
+const REG8_NAMES: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+const REG16_NAMES: [&str; 4] = ["bc", "de", "hl", "sp"];
+
+// Register pairs can't be copied directly, but their halves can (except `sp`'s)
+fn ld_reg16_error(dst: i32, src: i32) -> AssemblerError {
+    let (dst, src) = (dst as usize, src as usize);
+    let halves = if dst == REG_SP as usize || src == REG_SP as usize {
+        None
+    } else {
+        Some(format!(
+            "ld {}, {} / ld {}, {}",
+            REG8_NAMES[dst * 2],
+            REG8_NAMES[src * 2],
+            REG8_NAMES[dst * 2 + 1],
+            REG8_NAMES[src * 2 + 1],
+        ))
+    };
+    let instr = format!("ld {}, {}", REG16_NAMES[dst], REG16_NAMES[src]);
+    AssemblerError::NoSuchInstruction(instr, halves)
+}
+
+fn check_high_c(base: Expression, opcode: i32) -> Result<Instruction, AssemblerError> {
+    match base {
+        Expression::Known(0xff00) => Ok(Instruction::NoArg(opcode)),
+        base => Err(AssemblerError::BadHighCBase(base.to_string())),
+    }
+}
+
+// Values that only the linker can compute are range-checked by it instead
+fn check_range<T>(
+    arg: &Expression,
+    check: impl Fn(&Expression) -> Result<T, AssemblerError>,
+) -> Result<(), AssemblerError> {
+    match check(arg) {
+        Ok(_) | Err(AssemblerError::ExprNotConstant(_)) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+impl Instruction {
+    // Picks the encoding of `ld dst, src`, if there is one
+    pub fn ld(dst: Operand, src: Operand) -> Result<Self, AssemblerError> {
+        use Operand::*;
+
+        Ok(match (dst, src) {
+            (Reg8(REG_HL_IND), Reg8(REG_HL_IND)) => return Err(AssemblerError::LdHLHL),
+            (Reg8(dst), Reg8(src)) => Self::NoArg(0x40 | dst << 3 | src),
+            (Reg8(dst), Imm(val)) => Self::Arg8(0x06 | dst << 3, val),
+            (Reg16(dst), Imm(val)) => Self::Arg16(0x01 | dst << 4, val),
+            (Reg16(REG_SP), Reg16(REG_HL)) => Self::NoArg(0xf9),
+            (Reg16(dst), Reg16(src)) => return Err(ld_reg16_error(dst, src)),
+            (MemImm(addr), Reg16(REG_SP)) => Self::Arg16(0x08, addr),
+            // TODO: `optimizeloads`
+            (MemImm(addr), Reg8(REG_A)) => Self::Arg16(0xea, addr),
+            (Reg8(REG_A), MemImm(addr)) => Self::Arg16(0xfa, addr),
+            (HighMem(addr), Reg8(REG_A)) => Self::Arg8(0xe0, addr.check_hram()?),
+            (Reg8(REG_A), HighMem(addr)) => Self::Arg8(0xf0, addr.check_hram()?),
+            (HighC(base), Reg8(REG_A)) => check_high_c(base, 0xe2)?,
+            (Reg8(REG_A), HighC(base)) => check_high_c(base, 0xf2)?,
+            (Reg16Indirect(reg), Reg8(REG_A)) => Self::NoArg(0x02 | reg << 4),
+            (Reg8(REG_A), Reg16Indirect(reg)) => Self::NoArg(0x0a | reg << 4),
+            _ => return Err(AssemblerError::BadLdOperands),
+        })
+    }
+
+    pub fn emit(self, asm: &Assembler) -> Result<(), AssemblerError> {
+        match self {
+            Self::NoArg(opcode) => asm.emit_bytes(&[opcode as u8]),
+            Self::Arg8(opcode, arg) => {
+                check_range(&arg, Expression::as_u8)?;
+                asm.emit_bytes(&[opcode as u8])?;
+                asm.emit_byte(arg)
+            }
+            Self::Arg16(opcode, arg) => {
+                check_range(&arg, Expression::as_u16)?;
+                asm.emit_bytes(&[opcode as u8])?;
+                asm.emit_word(arg)
+            }
+            Self::Jp(opcode, target) => {
+                check_range(&target, Expression::as_u16)?;
+                asm.check_jp_range(&target);
+                asm.emit_bytes(&[opcode as u8])?;
+                asm.emit_word(target)
+            }
+            Self::Jr(opcode, target) => {
+                asm.check_jr_range(&target)?;
+                asm.emit_bytes(&[opcode as u8])?;
+                asm.emit_jr(target)
+            }
+            // The offset is signed, so e.g. 200 can't be written as -56
+            Self::SpOfs(opcode, ofs) => {
+                check_range(&ofs, Expression::as_i8).map_err(|err| match err {
+                    AssemblerError::ValueOutOfRange8(val) => AssemblerError::SpOffsetRange(val),
+                    err => err,
+                })?;
+                asm.emit_bytes(&[opcode as u8])?;
+                asm.emit_byte(ofs)
+            }
+            Self::Rst(addr) => {
+                let addr = i32::try_from(addr)?;
+                if addr & !0x38 != 0 {
+                    return Err(AssemblerError::BadRstTarget(addr));
+                }
+                asm.emit_bytes(&[0xc7 | addr as u8])
+            }
+        }
+    }
+}
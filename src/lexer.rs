@@ -1,6 +1,9 @@
-use crate::{Assembler, AssemblerError, DiagCallback, Diagnostic};
-use std::cell::RefCell;
+use crate::expression;
+use crate::{Assembler, AssemblerError, SpannedError, Warning};
+use std::cell::{Cell, RefCell};
+use std::char::REPLACEMENT_CHARACTER;
 use std::fmt::{self, Display, Formatter};
+use std::io::{self, BufReader, Bytes, Read};
 use std::iter::Peekable;
 use std::num::Wrapping;
 use std::rc::Rc;
@@ -80,6 +83,8 @@ pub enum TokType {
     Def,
     Fragment,
     Bank,
+    Sizeof,
+    Startof,
     Align,
     Round,
     Ceil,
@@ -104,6 +109,8 @@ pub enum TokType {
     Strupr,
     Strlwr,
     Include,
+    Print,
+    Println,
     Printt,
     Printi,
     Printv,
@@ -135,6 +142,9 @@ pub enum TokType {
     Shift,
     Rept,
     Endr,
+    For,
+    While,
+    Break,
     Load,
     Endl,
     If,
@@ -156,6 +166,7 @@ pub enum TokType {
     Rw,
     Equ,
     Equs,
+    Redef,
     Pushs,
     Pops,
     Pusho,
@@ -180,6 +191,8 @@ pub enum TokType {
     OpBinAnd,
     OpBinOr,
     OpBinXor,
+    OpShl,
+    OpShr,
     //   Arithmetic
     OpAdd,
     OpSub,
@@ -196,6 +209,7 @@ pub enum TokType {
 
     // Values
     Num(i32),
+    Gfx(i32),
     String(String),
     Ident(String),
     LocalIdent(String),
@@ -220,6 +234,61 @@ impl TokType {
             (true, true) => TokType::LocalLabel(name),
         }
     }
+
+    // Instructions, as opposed to directives, registers, etc.
+    fn is_mnemonic(&self) -> bool {
+        use TokType::*;
+
+        matches!(
+            self,
+            Adc | Add
+                | And
+                | Bit
+                | Call
+                | Ccf
+                | Cpl
+                | Cp
+                | Daa
+                | Dec
+                | Di
+                | Ei
+                | Halt
+                | Inc
+                | Jp
+                | Jr
+                | Ld
+                | Ldi
+                | Ldd
+                | Ldio
+                | Ldh
+                | Nop
+                | Or
+                | Pop
+                | Push
+                | Res
+                | Reti
+                | Ret
+                | Rlca
+                | Rlc
+                | Rla
+                | Rl
+                | Rrc
+                | Rrca
+                | Rra
+                | Rr
+                | Rst
+                | Sbc
+                | Scf
+                | Set
+                | Sla
+                | Sra
+                | Srl
+                | Stop
+                | Sub
+                | Swap
+                | Xor
+        )
+    }
 }
 
 // Include token names generated by the build script from the parser token names
@@ -227,10 +296,57 @@ include!(concat!(env!("OUT_DIR"), "/token_names.rs"));
 // Include the keyword trie, generated like the above
 include!(concat!(env!("OUT_DIR"), "/keywords.rs"));
 
+// === Keyword lookup ===
+
+fn find_keyword(name: &str) -> Option<&'static TokType> {
+    name.chars()
+        .try_fold(&KEYWORDS, |node, c| {
+            node.children.get(Lexer::to_index(c))?.as_ref()
+        })?
+        .value
+        .as_ref()
+}
+
+// Case-insensitive Levenshtein distance
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<_> = b.chars().map(|c| c.to_ascii_lowercase()).collect();
+    let mut row: Vec<_> = (0..=b.len()).collect();
+
+    for (i, a) in a.chars().map(|c| c.to_ascii_lowercase()).enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for j in 0..b.len() {
+            let subst = diag + usize::from(a != b[j]);
+            diag = row[j + 1];
+            row[j + 1] = subst.min(row[j] + 1).min(diag + 1);
+        }
+    }
+    row[b.len()]
+}
+
+// The instruction closest to a misspelled one, if any is close enough
+pub fn suggest_mnemonic(name: &str) -> Option<&'static str> {
+    let max_dist = (name.len() + 1) / 3;
+    KEYWORD_NAMES
+        .iter()
+        .filter(|kw| find_keyword(kw).is_some_and(TokType::is_mnemonic))
+        .map(|kw| (edit_distance(name, kw), *kw))
+        .filter(|&(dist, _)| dist <= max_dist)
+        .min_by_key(|&(dist, _)| dist)
+        .map(|(_, kw)| kw)
+}
+
+// Names built by interpolation may end up being anything
+pub(crate) fn is_symbol_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(Lexer::starts_ident) && chars.all(Lexer::is_ident)
+}
+
 // === Token location ===
 
 #[derive(Debug, Clone)]
 pub struct Location {
+    #[allow(dead_code)] // TODO: used for INCLUDE and macro backtraces
     parent: Option<Rc<Location>>,
     line_no: u32,
     col_no: u32,
@@ -246,12 +362,23 @@ impl Default for Location {
     }
 }
 
+impl Location {
+    pub fn line_no(&self) -> u32 {
+        self.line_no
+    }
+
+    pub fn col_no(&self) -> u32 {
+        self.col_no
+    }
+}
+
 impl Display for Location {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
         write!(fmt, "???:{}:{}", self.line_no, self.col_no)
     }
 }
 
+// The end is exclusive, i.e. it points just past the last char of the lexeme
 #[derive(Debug)]
 pub struct LocationSpan<'a> {
     begin: &'a Location,
@@ -276,10 +403,150 @@ impl Display for LocationSpan<'_> {
 
 // === Utilities ===
 
+// Tabs advance to the next tab stop, like in most editors
+pub(crate) fn advance_col(col: u32, c: char, tab_width: u8) -> u32 {
+    match c {
+        '\t' => ((col - 1) / u32::from(tab_width) + 1) * u32::from(tab_width) + 1,
+        _ => col + 1,
+    }
+}
+
+// Decodes UTF-8 from a reader on the fly, so the source never has to be fully loaded in memory
+// Invalid sequences are replaced with U+FFFD; I/O errors end iteration and are stored in `error`
+pub struct CharReader<'a, R: Read> {
+    bytes: Peekable<Bytes<BufReader<R>>>,
+    error: &'a RefCell<Option<io::Error>>,
+}
+
+impl<'a, R: Read> CharReader<'a, R> {
+    pub fn new(reader: R, error: &'a RefCell<Option<io::Error>>) -> Self {
+        Self {
+            bytes: BufReader::new(reader).bytes().peekable(),
+            error,
+        }
+    }
+
+    fn next_byte(&mut self) -> Option<u8> {
+        match self.bytes.next()? {
+            Ok(byte) => Some(byte),
+            Err(err) => {
+                self.error.replace(Some(err));
+                None
+            }
+        }
+    }
+
+    fn next_cont_byte(&mut self) -> Option<u8> {
+        match self.bytes.peek() {
+            Some(Ok(byte)) if byte & 0xc0 == 0x80 => self.next_byte(),
+            _ => None,
+        }
+    }
+}
+
+impl<R: Read> Iterator for CharReader<'_, R> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let first = self.next_byte()?;
+        // Number of continuation bytes, initial bits, and smallest non-overlong value
+        let (len, val, min) = match first {
+            0x00..=0x7f => return Some(first.into()),
+            0xc0..=0xdf => (1, first & 0x1f, 0x80),
+            0xe0..=0xef => (2, first & 0x0f, 0x800),
+            0xf0..=0xf7 => (3, first & 0x07, 0x10000),
+            _ => return Some(REPLACEMENT_CHARACTER),
+        };
+        let mut val = u32::from(val);
+
+        for _ in 0..len {
+            match self.next_cont_byte() {
+                Some(byte) => val = val << 6 | u32::from(byte & 0x3f),
+                None => return Some(REPLACEMENT_CHARACTER),
+            }
+        }
+        if val < min {
+            return Some(REPLACEMENT_CHARACTER);
+        }
+        Some(std::char::from_u32(val).unwrap_or(REPLACEMENT_CHARACTER))
+    }
+}
+
+// Iterates over an included file's contents
+struct IncludeChars {
+    contents: String,
+    ofs: usize,
+}
+
+impl Iterator for IncludeChars {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.contents[self.ofs..].chars().next()?;
+        self.ofs += c.len_utf8();
+        Some(c)
+    }
+}
+
+// Iterates over a macro's or loop's body, substituting arguments only as they are reached, so
+// that `SHIFT` affects the lines after it
+struct MacroChars<'a, 'b> {
+    body: Rc<String>,
+    ofs: usize,
+    // What `\@` expands to, distinct for each invocation or iteration
+    unique_id: u32,
+    // The argument being substituted, in reverse order
+    arg: Vec<char>,
+    // Shared with the lexer, e.g. so that comments are left alone
+    disabled: Rc<Cell<bool>>,
+    assembler: &'a Assembler<'b>,
+}
+
+impl Iterator for MacroChars<'_, '_> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        loop {
+            if let Some(c) = self.arg.pop() {
+                return Some(c);
+            }
+            let mut chars = self.body[self.ofs..].chars();
+            let c = chars.next()?;
+            self.ofs += c.len_utf8();
+            if c != '\\' || self.disabled.get() {
+                return Some(c);
+            }
+
+            let index = match chars.next() {
+                Some(digit @ '1'..='9') => digit.to_digit(10).unwrap() as usize,
+                Some('@') => {
+                    self.ofs += 1;
+                    self.arg = format!("_u{}", self.unique_id).chars().rev().collect();
+                    continue;
+                }
+                // An escaped backslash can't begin a macro argument
+                Some('\\') => {
+                    self.ofs += 1;
+                    self.arg.push('\\');
+                    return Some(c);
+                }
+                _ => return Some(c),
+            };
+            self.ofs += 1;
+            match self.assembler.macro_arg(index) {
+                Ok(arg) => self.arg = arg.chars().rev().collect(),
+                Err(err) => self.assembler.report_error(err),
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 enum InterpType {
     Binary,
     Decimal,
+    Fixed,
+    Octal,
     Upperhex,
     Lowerhex,
 }
@@ -298,6 +565,8 @@ impl InterpFmt {
         match chars.next() {
             Some('b') => ret.fmt = InterpType::Binary,
             Some('d') => ret.fmt = InterpType::Decimal,
+            Some('f') => ret.fmt = InterpType::Fixed,
+            Some('o') => ret.fmt = InterpType::Octal,
             Some('X') => ret.fmt = InterpType::Upperhex,
             Some('x') => ret.fmt = InterpType::Lowerhex,
             None => {
@@ -307,11 +576,22 @@ impl InterpFmt {
                 return Err(AssemblerError::BadInterpFmt(fmt));
             }
         }
-        if let Some(_) = chars.next() {
+        if chars.next().is_some() {
             return Err(AssemblerError::BadInterpFmt(fmt));
         }
         Ok(ret)
     }
+
+    fn format(&self, val: i32, fixed_precision: u8) -> String {
+        match self.fmt {
+            InterpType::Binary => expression::format_bin(val, 0),
+            InterpType::Decimal => format!("{}", val),
+            InterpType::Fixed => expression::format_fixed(val, 5, fixed_precision),
+            InterpType::Octal => expression::format_oct(val, 0),
+            InterpType::Upperhex => expression::format_hex(val, 0),
+            InterpType::Lowerhex => expression::format_hex(val, 0).to_lowercase(),
+        }
+    }
 }
 
 // === The lexer itself ===
@@ -321,44 +601,194 @@ pub enum State {
     Raw,
 }
 
+// Text lexed in place of the source, until it runs out
+pub enum Expansion {
+    Include(Vec<u8>),
+    Macro(Rc<String>),
+}
+
+// What has to be done once an expansion ends
+enum ExpansionKind {
+    Include,
+    Macro,
+    // The contents of an `EQUS` symbol, substituted for its name
+    Equs,
+    // The body is kept for the next iteration
+    Loop {
+        body: Rc<String>,
+        next: LoopNext,
+        // How many `IF`s were open when the loop began, so that `BREAK` can close the others
+        cond_depth: usize,
+    },
+}
+
+enum LoopNext {
+    // The condition is re-checked at the end of each iteration
+    While {
+        iterations: usize,
+    },
+    // `REPT` and `FOR` know how many iterations are left
+    Count {
+        remaining: usize,
+        var: Option<LoopVar>,
+    },
+}
+
+// `FOR`'s variable, and what is added to it after each iteration
+pub struct LoopVar {
+    pub name: String,
+    pub value: i32,
+    pub step: i32,
+}
+
+// Where skipping an `IF`'s lines stops
+enum Skip {
+    // The next `ELIF`, `ELSE`, or `ENDC`
+    Branch,
+    Endc,
+}
+
 pub struct MutState {
     state: State,
-    bin_digits: [char; 2],
-    gfx_digits: [char; 4],
+    // Requests from the parser, carried out before lexing the next line
+    macro_def: Option<String>,
+    loop_def: Option<bool>,
+    rept_def: Option<(usize, Option<LoopVar>)>,
+    loop_break: bool,
+    skip: Option<Skip>,
+    expansion: Option<Expansion>,
+    // Whether each enclosing `IF` has had one of its branches assembled
+    conditionals: Vec<bool>,
 }
 
 impl MutState {
     pub fn set_state(&mut self, state: State) {
         self.state = state;
     }
+
+    // The macro's body is read verbatim up to its `ENDM`, instead of being lexed
+    pub fn capture_macro(&mut self, name: String) {
+        self.macro_def = Some(name);
+    }
+
+    // Likewise for a loop's body, up to its `ENDR`, which is only replayed if `run` is set
+    pub fn capture_loop(&mut self, run: bool) {
+        self.loop_def = Some(run);
+    }
+
+    // `REPT` and `FOR` run their body `count` times instead
+    pub fn capture_rept(&mut self, count: usize, var: Option<LoopVar>) {
+        self.rept_def = Some((count, var));
+    }
+
+    // The rest of the innermost loop, including its remaining iterations, is skipped
+    pub fn break_loop(&mut self) {
+        self.loop_break = true;
+    }
+
+    // Unless `cond` is set, lines are skipped up to the `IF`'s next branch
+    pub fn begin_conditional(&mut self, cond: bool) {
+        self.conditionals.push(cond);
+        if !cond {
+            self.skip = Some(Skip::Branch);
+        }
+    }
+
+    // `ELIF` and `ELSE`; conditions are only checked if no branch has been assembled yet
+    pub fn next_branch(
+        &mut self,
+        keyword: &'static str,
+        cond: impl FnOnce() -> Result<bool, AssemblerError>,
+    ) -> Result<(), AssemblerError> {
+        let taken = self
+            .conditionals
+            .last_mut()
+            .ok_or(AssemblerError::OutsideConditional(keyword))?;
+        if *taken {
+            self.skip = Some(Skip::Endc);
+            return Ok(());
+        }
+        let cond = cond();
+        *taken = matches!(cond, Ok(true));
+        if !*taken {
+            self.skip = Some(Skip::Branch);
+        }
+        cond.map(|_| ())
+    }
+
+    pub fn end_conditional(&mut self) -> Result<(), AssemblerError> {
+        self.conditionals
+            .pop()
+            .map(|_| ())
+            .ok_or(AssemblerError::OutsideConditional("ENDC"))
+    }
+
+    pub fn in_conditional(&self) -> bool {
+        !self.conditionals.is_empty()
+    }
+
+    pub fn expand(&mut self, expansion: Expansion) {
+        self.expansion = Some(expansion);
+    }
+}
+
+// Guards against loops whose condition never becomes false
+const MAX_LOOP_ITERATIONS: usize = 65536;
+
+type CharStream<'a> = Peekable<Box<dyn Iterator<Item = char> + 'a>>;
+
+// The symbol names given to some directives must not be expanded
+enum EqusMode {
+    Expand,
+    KeepNextIdent,
+    KeepLine,
 }
 
-pub struct Lexer<'a> {
-    chars: Peekable<Box<dyn Iterator<Item = char> + 'a>>,
+pub struct Lexer<'a, 'b> {
+    chars: CharStream<'a>,
     loc: Location,
-    disable_macro_args: bool,
+    // Where the token being lexed began, so that errors can cover the whole lexeme
+    tok_begin: Location,
+    // Inputs suspended by an expansion, resumed once it runs out
+    parents: Vec<(CharStream<'a>, Location, ExpansionKind)>,
+    disable_macro_args: Rc<Cell<bool>>,
+    // The rest of the last `WHILE` line, replayed to re-check the loop's condition
+    loop_header: Option<String>,
+    interp_depth: usize,
+    equs_mode: EqusMode,
+    // How many `EQUS` have been expanded in a row without yielding a token
+    equs_depth: usize,
+    // Halting only takes effect between lines, so that the parser isn't left mid-line
+    at_line_start: bool,
+    // Only kept track of for the listing
+    line_text: String,
 
     state: &'a RefCell<MutState>,
-    diagnose: &'a DiagCallback,
-    assembler: &'a Assembler<'a>,
+    assembler: &'a Assembler<'b>,
 }
 
-impl<'a> Lexer<'a> {
+impl<'a, 'b> Lexer<'a, 'b> {
     pub fn new(
         iter: impl Iterator<Item = char> + 'a,
         state: &'a RefCell<MutState>,
-        diagnose: &'a DiagCallback,
-        assembler: &'a Assembler<'a>,
+        assembler: &'a Assembler<'b>,
     ) -> Self {
         let chs = [1, 2];
         assert!(chs.len() == 2);
         Self {
             chars: (Box::new(iter) as Box<dyn Iterator<Item = char>>).peekable(),
             loc: Default::default(),
-            disable_macro_args: false,
+            tok_begin: Default::default(),
+            parents: Vec::new(),
+            disable_macro_args: Rc::new(Cell::new(false)),
+            loop_header: None,
+            interp_depth: 0,
+            equs_mode: EqusMode::Expand,
+            equs_depth: 0,
+            at_line_start: true,
+            line_text: String::new(),
 
             state,
-            diagnose,
             assembler,
         }
     }
@@ -366,8 +796,13 @@ impl<'a> Lexer<'a> {
     pub fn new_state() -> MutState {
         MutState {
             state: State::Normal,
-            bin_digits: ['0', '1'],
-            gfx_digits: ['0', '1', '2', '3'],
+            macro_def: None,
+            loop_def: None,
+            rept_def: None,
+            loop_break: false,
+            skip: None,
+            expansion: None,
+            conditionals: Vec::new(),
         }
     }
 
@@ -388,28 +823,384 @@ impl<'a> Lexer<'a> {
     // Lexer internal management
 
     fn peek(&mut self) -> Option<&char> {
+        while self.chars.peek().is_none() && self.end_expansion() {}
         self.chars.peek()
     }
 
     fn next(&mut self) -> Option<char> {
+        while self.chars.peek().is_none() && self.end_expansion() {}
         let c = self.chars.next();
         match c {
             Some('\n') => {
                 self.loc.line_no += 1;
                 self.loc.col_no = 1;
             }
-            Some(_) => {
-                self.loc.col_no += 1;
+            Some(c) => {
+                self.loc.col_no = advance_col(self.loc.col_no, c, self.assembler.tab_width());
             }
             None => (),
         }
+
+        // What an `EQUS` expands to is not part of the source line
+        if self.in_equs() {
+            return c;
+        }
+        if let Some(header) = self
+            .loop_header
+            .as_mut()
+            .filter(|header| !header.ends_with('\n'))
+        {
+            header.extend(c);
+        }
+        if self.assembler.is_listing() {
+            match c {
+                Some('\n') => self
+                    .assembler
+                    .list_source_line(std::mem::take(&mut self.line_text)),
+                Some(c) => self.line_text.push(c),
+                None if !self.line_text.is_empty() => self
+                    .assembler
+                    .list_source_line(std::mem::take(&mut self.line_text)),
+                None => (),
+            }
+        }
         c
     }
 
+    // Errors found while reading a token span all of it so far
+    fn report_error(&self, err: AssemblerError) {
+        self.assembler
+            .report_error(err.at(self.tok_begin.clone(), self.loc.clone()));
+    }
+
+    fn begin_expansion(&mut self, expansion: Expansion) {
+        let (chars, kind): (Box<dyn Iterator<Item = char>>, _) = match expansion {
+            Expansion::Include(contents) => {
+                // Macros are limited separately, when they are entered
+                let max_depth = self.assembler.max_recursion_depth();
+                if self.parents.len() >= max_depth {
+                    self.assembler
+                        .report_error(AssemblerError::Recursion(max_depth));
+                    return;
+                }
+                let mut contents = String::from_utf8_lossy(&contents).into_owned();
+                // Otherwise, the file's last line would be joined with the line after `INCLUDE`
+                if !contents.is_empty() && !contents.ends_with('\n') {
+                    contents.push('\n');
+                }
+                (
+                    Box::new(IncludeChars { contents, ofs: 0 }),
+                    ExpansionKind::Include,
+                )
+            }
+            Expansion::Macro(body) => return self.push_body(body, ExpansionKind::Macro),
+        };
+        self.push_expansion(chars, kind);
+    }
+
+    // Macro and loop bodies get their own `\@`, which also keeps their local labels apart
+    fn push_body(&mut self, body: Rc<String>, kind: ExpansionKind) {
+        let unique_id = self.assembler.new_unique_id();
+        self.assembler.enter_expansion(unique_id);
+        self.push_expansion(
+            Box::new(MacroChars {
+                body,
+                ofs: 0,
+                unique_id,
+                arg: Vec::new(),
+                disabled: Rc::clone(&self.disable_macro_args),
+                assembler: self.assembler,
+            }),
+            kind,
+        );
+    }
+
+    fn push_expansion(&mut self, chars: Box<dyn Iterator<Item = char> + 'a>, kind: ExpansionKind) {
+        let loc = Location {
+            parent: Some(Rc::new(self.loc.clone())),
+            line_no: 1,
+            col_no: 1,
+        };
+        self.parents.push((
+            std::mem::replace(&mut self.chars, chars.peekable()),
+            std::mem::replace(&mut self.loc, loc),
+            kind,
+        ));
+    }
+
+    fn in_equs(&self) -> bool {
+        matches!(self.parents.last(), Some((_, _, ExpansionKind::Equs)))
+    }
+
+    // Returns whether the identifier was an `EQUS` symbol, which is then lexed in its place
+    fn expand_equs(&mut self, name: &str) -> Result<bool, AssemblerError> {
+        let contents = match self.assembler.find_symbol(&name.to_string()) {
+            Some(sym) => match sym.get_str() {
+                Some(contents) => {
+                    sym.mark_referenced();
+                    contents.clone()
+                }
+                None => return Ok(false),
+            },
+            None => return Ok(false),
+        };
+
+        // Otherwise, a symbol expanding to itself would never stop; the expansion has usually
+        // ended by the time the name has been read, so its nesting can't be used for this
+        let max_depth = self.assembler.max_recursion_depth();
+        if self.equs_depth >= max_depth {
+            return Err(AssemblerError::Recursion(max_depth));
+        }
+        self.equs_depth += 1;
+        let chars = Box::new(IncludeChars { contents, ofs: 0 });
+        self.push_expansion(chars, ExpansionKind::Equs);
+        Ok(true)
+    }
+
+    // Resumes lexing what the innermost expansion was suspending
+    fn pop_expansion(&mut self) -> Option<ExpansionKind> {
+        let (chars, loc, kind) = self.parents.pop()?;
+        self.chars = chars;
+        self.loc = loc;
+        if let ExpansionKind::Macro | ExpansionKind::Loop { .. } = kind {
+            self.assembler.leave_expansion();
+        }
+        Some(kind)
+    }
+
+    // Returns whether there was an expansion to end
+    fn end_expansion(&mut self) -> bool {
+        match self.pop_expansion() {
+            Some(kind) => {
+                match kind {
+                    ExpansionKind::Macro => self.assembler.end_macro(),
+                    ExpansionKind::Loop {
+                        body,
+                        next: LoopNext::Count { remaining, var },
+                        ..
+                    } => self.next_iteration(body, remaining, var),
+                    _ => (),
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
     // Lexer helpers
 
+    // Whether the line begins with the given keyword, which must be lowercase
+    fn starts_with_keyword(line: &str, keyword: &str) -> bool {
+        let line = line.trim_start_matches(Self::is_whitespace);
+        line.get(..keyword.len())
+            .is_some_and(|kw| kw.eq_ignore_ascii_case(keyword))
+            && !line[keyword.len()..].starts_with(Self::is_ident)
+    }
+
+    fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        while let Some(c) = self.next() {
+            line.push(c);
+            if c == '\n' {
+                break;
+            }
+        }
+        line
+    }
+
+    // Reads a macro's body line by line, up to and including the line with its `ENDM`
+    fn capture_macro(&mut self, name: String) {
+        self.disable_macro_args.set(true);
+        let mut body = String::new();
+        let terminated = loop {
+            let line = self.read_line();
+            if Self::starts_with_keyword(&line, "endm") {
+                break true;
+            }
+            body.push_str(&line);
+            if !line.ends_with('\n') {
+                break false;
+            }
+        };
+        self.disable_macro_args.set(false);
+
+        if !terminated {
+            self.assembler.report_error(AssemblerError::UntermMacro);
+        } else if let Err(err) = self.assembler.define_macro(name, body) {
+            self.assembler.report_error(err);
+        }
+    }
+
+    // Reads a loop's body up to its matching `ENDR`; arguments are substituted on each iteration
+    fn capture_loop_body(&mut self) -> Option<String> {
+        self.disable_macro_args.set(true);
+        let mut body = String::new();
+        let mut depth = 0;
+        let terminated = loop {
+            let line = self.read_line();
+            if Self::starts_with_keyword(&line, "endr") {
+                if depth == 0 {
+                    break true;
+                }
+                depth -= 1;
+            } else if ["rept", "for", "while"]
+                .iter()
+                .any(|keyword| Self::starts_with_keyword(&line, keyword))
+            {
+                depth += 1;
+            }
+            body.push_str(&line);
+            if !line.ends_with('\n') {
+                break false;
+            }
+        };
+        self.disable_macro_args.set(false);
+        Some(body).filter(|_| terminated)
+    }
+
+    fn capture_loop(&mut self, run: bool) {
+        let header = self.loop_header.take().unwrap_or_default();
+
+        // Re-checking the condition is the last thing an iteration does
+        let (body, iterations) = match self.parents.last() {
+            Some((
+                _,
+                _,
+                ExpansionKind::Loop {
+                    next: LoopNext::While { .. },
+                    ..
+                },
+            )) if self.chars.peek().is_none() => match self.pop_expansion().unwrap() {
+                ExpansionKind::Loop {
+                    body,
+                    next: LoopNext::While { iterations },
+                    ..
+                } => (body, iterations),
+                _ => unreachable!(),
+            },
+            _ => match self.capture_loop_body() {
+                Some(body) => (Rc::new(body), 0),
+                None => {
+                    self.assembler.report_error(AssemblerError::UntermLoop);
+                    return;
+                }
+            },
+        };
+
+        if !run {
+            return;
+        }
+        if iterations == MAX_LOOP_ITERATIONS {
+            self.assembler
+                .report_error(AssemblerError::WhileLimitExceeded(MAX_LOOP_ITERATIONS));
+            return;
+        }
+        self.push_body(
+            Rc::new(format!("{}WHILE{}", body, header)),
+            ExpansionKind::Loop {
+                body,
+                next: LoopNext::While {
+                    iterations: iterations + 1,
+                },
+                cond_depth: self.state.borrow().conditionals.len(),
+            },
+        );
+    }
+
+    fn capture_rept(&mut self, count: usize, var: Option<LoopVar>) {
+        match self.capture_loop_body() {
+            Some(body) if count != 0 => self.push_iteration(Rc::new(body), count - 1, var),
+            Some(_) => (),
+            None => self.assembler.report_error(AssemblerError::UntermLoop),
+        }
+    }
+
+    fn push_iteration(&mut self, body: Rc<String>, remaining: usize, var: Option<LoopVar>) {
+        self.push_body(
+            Rc::clone(&body),
+            ExpansionKind::Loop {
+                body,
+                next: LoopNext::Count { remaining, var },
+                cond_depth: self.state.borrow().conditionals.len(),
+            },
+        );
+    }
+
+    // Like in RGBDS, `FOR`'s variable is updated even after the last iteration
+    fn next_iteration(&mut self, body: Rc<String>, remaining: usize, mut var: Option<LoopVar>) {
+        if let Some(var) = &mut var {
+            var.value = var.value.wrapping_add(var.step);
+            if let Err(err) = self.assembler.set_loop_var(&var.name, var.value) {
+                self.assembler.report_error(err);
+            }
+        }
+        if remaining != 0 {
+            self.push_iteration(body, remaining - 1, var);
+        }
+    }
+
+    // Leaves the innermost loop, along with any `EQUS` expanded within it; like RGBDS, loops
+    // aren't left from within a macro or an included file
+    fn break_loop(&mut self) {
+        let depth = self
+            .parents
+            .iter()
+            .rposition(|(_, _, kind)| !matches!(kind, ExpansionKind::Equs));
+        match depth {
+            Some(depth) if matches!(self.parents[depth].2, ExpansionKind::Loop { .. }) => {
+                self.parents.truncate(depth + 1);
+                if let Some(ExpansionKind::Loop { cond_depth, .. }) = self.pop_expansion() {
+                    self.state.borrow_mut().conditionals.truncate(cond_depth);
+                }
+            }
+            _ => self
+                .assembler
+                .report_error(AssemblerError::BreakOutsideLoop),
+        }
+    }
+
+    // Skips the lines of an `IF`'s branches that aren't assembled, returning the `ELIF`, `ELSE`,
+    // or `ENDC` token that ends them; the rest of its line is lexed as usual
+    fn skip_conditional(&mut self, skip: Skip) -> Option<Token> {
+        let mut depth = 0;
+        loop {
+            while self.peek().is_some_and(|&c| Self::is_whitespace(c)) {
+                self.next();
+            }
+            let begin = self.loc.clone();
+            let mut keyword = String::new();
+            while let Some(&c) = self.peek().filter(|&&c| Self::is_ident(c)) {
+                keyword.push(c.to_ascii_lowercase());
+                self.next();
+            }
+
+            let tok_type = match keyword.as_str() {
+                "if" => {
+                    depth += 1;
+                    None
+                }
+                "endc" if depth != 0 => {
+                    depth -= 1;
+                    None
+                }
+                "endc" => Some(TokType::Endc),
+                "elif" if depth == 0 && matches!(skip, Skip::Branch) => Some(TokType::Elif),
+                "else" if depth == 0 && matches!(skip, Skip::Branch) => Some(TokType::Else),
+                _ => None,
+            };
+            if let Some(tok_type) = tok_type {
+                self.tok_begin = begin.clone();
+                return Some((begin, tok_type, self.loc.clone()));
+            }
+            // Unterminated conditionals are reported once the parser is done
+            if !self.read_line().ends_with('\n') {
+                return None;
+            }
+        }
+    }
+
     fn discard_comment(&mut self) {
-        self.disable_macro_args = true;
+        self.disable_macro_args.set(true);
         loop {
             match self.peek() {
                 Some('\r') => break,
@@ -419,11 +1210,11 @@ impl<'a> Lexer<'a> {
             }
             self.next();
         }
-        self.disable_macro_args = false;
+        self.disable_macro_args.set(false);
     }
 
     fn discard_block_comment(&mut self) {
-        self.disable_macro_args = true;
+        self.disable_macro_args.set(true);
         loop {
             match self.next() {
                 Some('*') => {
@@ -436,7 +1227,7 @@ impl<'a> Lexer<'a> {
                 _ => (),
             }
         }
-        self.disable_macro_args = false;
+        self.disable_macro_args.set(false);
     }
 
     fn read_num(&mut self, radix: u32, first_char: char) -> Result<i32, AssemblerError> {
@@ -444,6 +1235,7 @@ impl<'a> Lexer<'a> {
         assert!(radix <= 16);
 
         let mut val = Wrapping(first_char.to_digit(radix).unwrap() as i32); // Truncation guaranteed (single digit)
+        let mut malformed = false;
         loop {
             let c: char;
             match self.peek() {
@@ -451,6 +1243,11 @@ impl<'a> Lexer<'a> {
                     c = *next;
                     self.next();
                 }
+                Some('_') => {
+                    self.next();
+                    malformed |= !self.peek().is_some_and(|c| c.is_digit(radix));
+                    continue;
+                }
                 _ => break,
             }
 
@@ -459,6 +1256,11 @@ impl<'a> Lexer<'a> {
                 None => break,
             }
         }
+
+        // The number is still usable, so keep lexing normally
+        if malformed {
+            self.report_error(AssemblerError::MalformedNumber);
+        }
         Ok(val.0)
     }
 
@@ -472,6 +1274,18 @@ impl<'a> Lexer<'a> {
                 self.next();
                 Ok(TokType::Num(self.read_num(radix, c)?))
             }
+            // If the prefix can't mean anything else, this is a number starting with a separator
+            Some('_') if alternative.is_err() => {
+                self.next();
+                self.report_error(AssemblerError::MalformedNumber);
+                match self.peek() {
+                    Some(&c) if c.is_digit(radix) => {
+                        self.next();
+                        Ok(TokType::Num(self.read_num(radix, c)?))
+                    }
+                    _ => alternative,
+                }
+            }
             _ => alternative,
         }
     }
@@ -489,45 +1303,132 @@ impl<'a> Lexer<'a> {
 
                 let mut c = *c;
                 let mut val = Wrapping(0_i32);
+                let mut malformed = false;
                 loop {
                     self.next();
                     val = val * radix
                         + Wrapping(digits.iter().position(|&ch| ch == c).unwrap() as i32);
                     match self.peek() {
                         Some(ch) if digits.contains(ch) => c = *ch,
+                        Some('_') => {
+                            self.next();
+                            match self.peek() {
+                                Some(ch) if digits.contains(ch) => c = *ch,
+                                _ => {
+                                    malformed = true;
+                                    break;
+                                }
+                            }
+                        }
                         _ => break,
                     }
                 }
+
+                if malformed {
+                    self.report_error(AssemblerError::MalformedNumber);
+                }
                 Ok(TokType::Num(val.0))
             }
             _ => alternative,
         }
     }
 
+    // Reads a number starting with `c`, two of the prefixes being ambiguous (!)
+    fn read_number(&mut self, c: char) -> Result<TokType, AssemblerError> {
+        Ok(match c {
+            '$' => self.read_prefixed_num(16, Err(AssemblerError::EmptyHex))?,
+            '0' if self.assembler.options().c_prefixes
+                && matches!(self.peek(), Some('x' | 'X' | 'b' | 'B')) =>
+            {
+                let prefix = self.next().unwrap();
+                let radix = if prefix.eq_ignore_ascii_case(&'x') {
+                    16
+                } else {
+                    2
+                };
+                self.read_prefixed_num(radix, Err(AssemblerError::EmptyCPrefix(prefix)))?
+            }
+            c if c.is_ascii_digit() => {
+                let mut val = self.read_num(10, c)?;
+                if let Some('.') = self.peek() {
+                    self.next();
+                    val = self.read_fractional(val)?;
+                }
+                TokType::Num(val)
+            }
+            '&' => {
+                // Either a logical AND, or an octal number or binary AND
+                if let Some('&') = self.peek() {
+                    self.next();
+                    TokType::OpAnd
+                } else {
+                    self.read_prefixed_num(8, Ok(TokType::OpBinAnd))?
+                }
+            }
+            '%' => {
+                let digits = self.assembler.options().bin_digits;
+                self.read_prefixed_custom_num(&digits, Ok(TokType::OpMod))?
+            }
+            '`' => self.read_gfx()?,
+            c => return Err(AssemblerError::GarbageChar(c)),
+        })
+    }
+
+    // Gfx constants are laid out like a row of Game Boy tile data: the low bit of each pixel
+    // goes in the low byte, and the high bit in the high byte
+    fn read_gfx(&mut self) -> Result<TokType, AssemblerError> {
+        let digits = self.assembler.options().gfx_digits;
+        let mut low_plane = 0_u8;
+        let mut high_plane = 0_u8;
+        let mut nb_pixels = 0;
+
+        while let Some(pixel) = self
+            .peek()
+            .and_then(|c| digits.iter().position(|ch| ch == c))
+        {
+            self.next();
+            low_plane = low_plane << 1 | (pixel & 1) as u8;
+            high_plane = high_plane << 1 | (pixel >> 1) as u8;
+            nb_pixels += 1;
+        }
+
+        if nb_pixels == 0 {
+            Err(AssemblerError::EmptyGfx)
+        } else {
+            Ok(TokType::Gfx(
+                i32::from(high_plane) << 8 | i32::from(low_plane),
+            ))
+        }
+    }
+
+    // Fixed-point numbers have `OPT Q` fractional bits; digits past what they can represent are
+    // ignored
     fn read_fractional(&mut self, int: i32) -> Result<i32, AssemblerError> {
-        // TODO: test this
-        let c = match self.peek() {
-            Some(c) if c.is_ascii_digit() => c,
+        const MAX_DIGITS: u32 = 9;
+
+        match self.peek() {
+            Some(c) if c.is_ascii_digit() => (),
             _ => return Err(AssemblerError::EmptyFract),
         };
 
-        let whole = i32::from(int as i16);
-        if whole != int {
-            // TODO: emit warning
-            unimplemented!()
-        }
-
-        let mut frac: u32 = c.to_digit(10).unwrap();
-        let mut len = 1;
-        loop {
+        let mut frac: u64 = 0;
+        let mut len = 0;
+        while let Some(digit) = self.peek().and_then(|c| c.to_digit(10)) {
             self.next();
-            match self.peek().and_then(|c| c.to_digit(10)) {
-                Some(digit) => frac = frac * 10 + digit,
-                None => break,
+            if len < MAX_DIGITS {
+                frac = frac * 10 + u64::from(digit);
+                len += 1;
             }
-            len += 1;
         }
-        Ok(whole << 16 | (frac * (2u32.pow(16 - len)) / 5u32.pow(len)) as i32)
+
+        let precision = self.assembler.options().fixed_precision;
+        // Sign-extended from the integer part's width
+        let whole = int << precision >> precision;
+        if whole != int {
+            self.assembler
+                .report_warning(Warning::TruncatedFixedPoint(int, 32 - precision));
+        }
+        Ok(whole << precision | ((frac << precision) / 10u64.pow(len)) as i32)
     }
 
     fn read_interpolation(&mut self) -> Result<String, AssemblerError> {
@@ -538,22 +1439,30 @@ impl<'a> Lexer<'a> {
             match self.peek() {
                 Some('{') => {
                     self.next();
-                    result.push_str(&self.read_interpolation()?);
+                    let max_depth = self.assembler.max_recursion_depth();
+                    if self.interp_depth >= max_depth {
+                        self.skip_interpolation();
+                        return Err(AssemblerError::Recursion(max_depth));
+                    }
+                    self.interp_depth += 1;
+                    let nested = self.read_interpolation();
+                    self.interp_depth -= 1;
+                    result.push_str(&nested?);
                 }
                 None => {
-                    (self.diagnose)(Diagnostic::Error(AssemblerError::UntermInterp.into()));
+                    self.report_error(AssemblerError::UntermInterp);
                     break;
                 }
                 Some('\r') => {
-                    (self.diagnose)(Diagnostic::Error(AssemblerError::UntermInterp.into()));
+                    self.report_error(AssemblerError::UntermInterp);
                     break;
                 }
                 Some('\n') => {
-                    (self.diagnose)(Diagnostic::Error(AssemblerError::UntermInterp.into()));
+                    self.report_error(AssemblerError::UntermInterp);
                     break;
                 }
                 Some('"') => {
-                    (self.diagnose)(Diagnostic::Error(AssemblerError::UntermInterp.into()));
+                    self.report_error(AssemblerError::UntermInterp);
                     break;
                 }
                 Some('}') => {
@@ -563,13 +1472,11 @@ impl<'a> Lexer<'a> {
                 Some(':') => {
                     self.next();
                     match fmt {
-                        Some(_) => (self.diagnose)(Diagnostic::Error(
-                            AssemblerError::MultipleInterpFmt.into(),
-                        )),
+                        Some(_) => self.report_error(AssemblerError::MultipleInterpFmt),
                         None => {
                             match InterpFmt::from_str(result) {
                                 Ok(f) => fmt = Some(f),
-                                Err(err) => (self.diagnose)(Diagnostic::Error(err.into())),
+                                Err(err) => self.report_error(err),
                             };
                             result = String::new();
                         }
@@ -581,64 +1488,109 @@ impl<'a> Lexer<'a> {
                 }
                 Some(&c) => {
                     self.next();
-                    (self.diagnose)(Diagnostic::Error(
-                        AssemblerError::IllegalInterpChar(c).into(),
-                    ));
+                    self.report_error(AssemblerError::IllegalInterpChar(c));
                 }
             }
         }
 
-        todo!();
+        if result.is_empty() {
+            return Err(AssemblerError::EmptyInterpName);
+        }
+        let name = self.assembler.expand_sym_name(result)?;
+        let sym = self
+            .assembler
+            .find_symbol(&name)
+            .ok_or_else(|| AssemblerError::InterpUndefined(name.clone()))?;
+        sym.mark_referenced();
+
+        if let Some(string) = sym.get_str() {
+            Ok(string.clone())
+        } else if let Some(val) = sym.get_value() {
+            Ok(match fmt {
+                Some(fmt) => fmt.format(val, self.assembler.options().fixed_precision),
+                None => format!("${}", expression::format_hex(val, 0)),
+            })
+        } else {
+            Err(AssemblerError::InterpNotConstant(name))
+        }
+    }
+
+    // Skips the rest of an interpolation, including all enclosing ones
+    fn skip_interpolation(&mut self) {
+        let mut depth = self.interp_depth + 2;
 
-        Ok(result)
+        while let Some(&c) = self.peek() {
+            match c {
+                '"' | '\r' | '\n' => break,
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => (),
+            }
+            self.next();
+            if depth == 0 {
+                break;
+            }
+        }
     }
 
-    fn read_str(&mut self) -> String {
+    // Reads a string or character constant, up to its (unescaped) closing quote
+    fn read_str(&mut self, quote: char) -> String {
         let mut s = String::new();
+        let unterminated = || {
+            if quote == '"' {
+                AssemblerError::UntermString
+            } else {
+                AssemblerError::UntermChar
+            }
+        };
 
         loop {
+            // The newline is left alone, since it still ends the line
+            if let Some('\r') | Some('\n') | None = self.peek() {
+                self.report_error(unterminated());
+                return s;
+            }
             match self.next() {
-                Some('"') => {
-                    return s;
-                }
-                Some('\r') => {
-                    (self.diagnose)(Diagnostic::Error(AssemblerError::UntermString.into()));
-                    return s;
-                }
-                Some('\n') => {
-                    (self.diagnose)(Diagnostic::Error(AssemblerError::UntermString.into()));
+                Some(c) if c == quote => {
                     return s;
                 }
-                None => {
-                    (self.diagnose)(Diagnostic::Error(AssemblerError::UntermString.into()));
-                    return s;
-                }
-
-                Some('\\') => match self.peek() {
-                    Some('\\') => s.push('\\'),
-                    Some('"') => s.push('"'),
-                    Some('{') => s.push('{'),
-                    Some('}') => s.push('}'),
-                    Some('n') => s.push('\n'),
-                    Some('r') => s.push('\r'),
-                    Some('t') => s.push('\t'),
+                None | Some('\r') | Some('\n') => unreachable!(),
 
-                    Some(&c) if Self::begins_line_cont(c) => self.read_line_cont(),
+                Some('\\') => {
+                    let escaped = match self.peek() {
+                        Some('\\') => '\\',
+                        Some('"') => '"',
+                        Some('\'') => '\'',
+                        Some('{') => '{',
+                        Some('}') => '}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('0') => '\0',
+
+                        Some(&c) if Self::begins_line_cont(c) => {
+                            self.read_line_cont();
+                            continue;
+                        }
 
-                    None => {
-                        (self.diagnose)(Diagnostic::Error(AssemblerError::IllegalEscapeEOF.into()));
-                        return s;
-                    }
-                    Some(&c) => {
-                        (self.diagnose)(Diagnostic::Error(AssemblerError::IllegalEscape(c).into()));
-                        return s;
-                    }
-                },
+                        None => {
+                            self.report_error(AssemblerError::IllegalEscapeEOF);
+                            return s;
+                        }
+                        Some(&c) => {
+                            self.next();
+                            self.report_error(AssemblerError::IllegalEscape(c));
+                            continue;
+                        }
+                    };
+                    self.next();
+                    s.push(escaped);
+                }
 
                 // Symbol interpolation
                 Some('{') => match self.read_interpolation() {
                     Ok(result) => s.push_str(&result),
-                    Err(err) => (self.diagnose)(Diagnostic::Error(err.into())),
+                    Err(err) => self.report_error(err),
                 },
 
                 Some(c) => s.push(c),
@@ -650,6 +1602,7 @@ impl<'a> Lexer<'a> {
         c == ' ' || c == '\r' || c == '\n' || c == ';'
     }
 
+    // Lexing goes on with the next physical line, whose locations are kept as-is
     fn read_line_cont(&mut self) {
         loop {
             match self.peek() {
@@ -658,6 +1611,9 @@ impl<'a> Lexer<'a> {
                 }
                 Some('\r') => {
                     self.next();
+                    if let Some('\n') = self.peek() {
+                        self.next();
+                    }
                     break;
                 }
                 Some('\n') => {
@@ -669,21 +1625,41 @@ impl<'a> Lexer<'a> {
                     self.discard_comment();
                 }
                 Some(&c) => {
-                    (self.diagnose)(Diagnostic::Error(
-                        AssemblerError::CharAfterLineCont(c).into(),
-                    ));
+                    // Point at the offending char, which is left for the next token
+                    let begin = self.loc.clone();
+                    let mut end = begin.clone();
+                    end.col_no += 1;
+                    self.assembler
+                        .report_error(AssemblerError::CharAfterLineCont(c).at(begin, end));
                     return;
                 }
                 None => {
-                    (self.diagnose)(Diagnostic::Error(AssemblerError::LineContEOF.into()));
+                    self.assembler.report_error(
+                        AssemblerError::LineContEOF.at(self.loc.clone(), self.loc.clone()),
+                    );
                     return;
                 }
             }
         }
     }
 
+    // Macro arguments never make it here, since they are substituted before being lexed
     fn read_escape(&mut self) -> Result<(), AssemblerError> {
-        unimplemented!()
+        match self.peek() {
+            Some(&c) if Self::begins_line_cont(c) => {
+                self.read_line_cont();
+                Ok(())
+            }
+            Some(&c) => {
+                self.next();
+                Err(match c {
+                    '1'..='9' => AssemblerError::MacroArgOutsideMacro,
+                    '@' => AssemblerError::UniqueIdOutsideMacro,
+                    c => AssemblerError::IllegalEscape(c),
+                })
+            }
+            None => Err(AssemblerError::IllegalEscapeEOF),
+        }
     }
 
     fn to_index(c: char) -> usize {
@@ -700,18 +1676,25 @@ impl<'a> Lexer<'a> {
         let mut ident = String::new();
         let mut c = first_char;
         let mut is_local = false;
-        let begun_at_bol = self.loc.col_no == 2; // `first_char` has been read before this
+        // `first_char` has been read before this
+        let begun_at_bol = self.loc.col_no == 2 && !self.in_equs();
 
         loop {
-            ident.push(c);
-            keyword = keyword.and_then(|node| {
-                node.children
-                    .get(Self::to_index(c))
-                    .and_then(Option::as_ref)
-            });
+            if c == '{' {
+                // The result is taken as-is, so that it can't be a keyword
+                ident.push_str(&self.read_interpolation()?);
+                keyword = None;
+            } else {
+                ident.push(c);
+                keyword = keyword.and_then(|node| {
+                    node.children
+                        .get(Self::to_index(c))
+                        .and_then(Option::as_ref)
+                });
+            }
 
             c = match self.peek() {
-                Some(&ch) if Self::is_ident(ch) => ch,
+                Some(&ch) if Self::is_ident(ch) || ch == '{' => ch,
                 _ => break,
             };
             if c == '.' {
@@ -724,27 +1707,42 @@ impl<'a> Lexer<'a> {
             self.next();
         }
 
-        // TODO: expand EQUS, and return Ok(None)
-
-        Ok(Some(
-            keyword
-                .and_then(|node| node.value.clone())
-                .unwrap_or(TokType::ident(ident, is_local, begun_at_bol)),
-        ))
+        if let Some(tok) = keyword.and_then(|node| node.value.clone()) {
+            return Ok(Some(tok));
+        }
+        // Interpolations may have supplied the dot
+        let is_local = is_local || ident.contains('.');
+        // Labels being defined are never expanded
+        if !is_local && !begun_at_bol {
+            match std::mem::replace(&mut self.equs_mode, EqusMode::Expand) {
+                EqusMode::Expand => {
+                    if self.expand_equs(&ident)? {
+                        return Ok(None);
+                    }
+                }
+                EqusMode::KeepNextIdent => (),
+                EqusMode::KeepLine => self.equs_mode = EqusMode::KeepLine,
+            }
+        }
+        Ok(Some(TokType::ident(ident, is_local, begun_at_bol)))
     }
 
     // The lexer proper
 
     fn next_normal_tok(&mut self) -> Result<Option<Token>, AssemblerError> {
         loop {
+            // The token may begin after the end of an expansion
+            self.peek();
             let begin = self.loc.clone();
+            self.tok_begin = begin.clone();
 
             if let Some(c) = self.next() {
                 let tok_type = match c {
                     // Unambiguous single-char tokens
                     '^' => TokType::OpBinXor,
-                    '+' => TokType::Add,
-                    '-' => TokType::Sub,
+                    '+' => TokType::OpAdd,
+                    '-' => TokType::OpSub,
+                    '*' => TokType::OpMul,
                     '~' => TokType::OpBinNot,
                     '[' => TokType::Lbrack,
                     ']' => TokType::Rbrack,
@@ -752,6 +1750,8 @@ impl<'a> Lexer<'a> {
                     ')' => TokType::Rparen,
                     ',' => TokType::Comma,
                     ':' => TokType::Colon,
+                    // The PC is looked up like any other symbol
+                    '@' => TokType::Ident("@".to_string()),
 
                     // Ambiguous 1- or 2-char tokens
                     '/' => {
@@ -760,14 +1760,14 @@ impl<'a> Lexer<'a> {
                             self.discard_block_comment();
                             continue;
                         } else {
-                            TokType::Div
+                            TokType::OpDiv
                         }
                     }
                     '|' => {
                         // Either a binary or logical OR
                         if let Some('|') = self.peek() {
                             self.next();
-                            TokType::Or
+                            TokType::OpOr
                         } else {
                             TokType::OpBinOr
                         }
@@ -782,21 +1782,31 @@ impl<'a> Lexer<'a> {
                         }
                     }
                     '<' => {
-                        // Either LT or LTE
-                        if let Some('=') = self.peek() {
-                            self.next();
-                            TokType::OpLte
-                        } else {
-                            TokType::OpLt
+                        // Either LT, LTE, or left shift
+                        match self.peek() {
+                            Some('=') => {
+                                self.next();
+                                TokType::OpLte
+                            }
+                            Some('<') => {
+                                self.next();
+                                TokType::OpShl
+                            }
+                            _ => TokType::OpLt,
                         }
                     }
                     '>' => {
-                        // Either GT or GTE
-                        if let Some('=') = self.peek() {
-                            self.next();
-                            TokType::OpGte
-                        } else {
-                            TokType::OpGt
+                        // Either GT, GTE, or right shift
+                        match self.peek() {
+                            Some('=') => {
+                                self.next();
+                                TokType::OpGte
+                            }
+                            Some('>') => {
+                                self.next();
+                                TokType::OpShr
+                            }
+                            _ => TokType::OpGt,
                         }
                     }
                     '!' => {
@@ -810,28 +1820,14 @@ impl<'a> Lexer<'a> {
                     }
 
                     // Numbers, two of the prefixes being ambiguous (!)
-                    '$' => self.read_prefixed_num(16, Err(AssemblerError::EmptyHex))?,
-                    c if c.is_ascii_digit() => {
-                        let val = self.read_num(10, c)?;
-                        if let Some('.') = self.peek() {
-                            self.next();
-                            self.read_fractional(val)?;
-                        }
-                        TokType::Num(val)
-                    }
-                    '&' => self.read_prefixed_num(8, Ok(TokType::OpBinAnd)).unwrap(),
-                    '%' => {
-                        let digits = self.state.borrow().bin_digits;
-                        self.read_prefixed_custom_num(&digits, Ok(TokType::OpMod))
-                            .unwrap()
-                    }
-                    '`' => {
-                        let digits = self.state.borrow().gfx_digits;
-                        self.read_prefixed_custom_num(&digits, Err(AssemblerError::EmptyGfx))?
-                    }
+                    '$' | '0'..='9' | '&' | '%' | '`' => self.read_number(c)?,
 
                     // Strings
-                    '"' => TokType::String(self.read_str()),
+                    '"' => TokType::String(self.read_str('"')),
+                    '\'' => {
+                        let string = self.read_str('\'');
+                        TokType::Num(self.assembler.char_value(&string)?)
+                    }
 
                     // End-of-line stuff
                     '\r' => TokType::CarriageRet,
@@ -854,8 +1850,8 @@ impl<'a> Lexer<'a> {
                         continue;
                     }
 
-                    // Identifiers, including keywords
-                    c if Self::starts_ident(c) => match self.read_ident(c)? {
+                    // Identifiers, including keywords, possibly built from interpolations
+                    c if Self::starts_ident(c) || c == '{' => match self.read_ident(c)? {
                         Some(tok) => tok,
                         None => continue,
                     },
@@ -881,30 +1877,31 @@ impl<'a> Lexer<'a> {
         }
 
         let begin = self.loc.clone();
+        self.tok_begin = begin.clone();
         let mut end = None;
 
         let mut arg = String::new();
         let mut inside_string = false;
+        // Commas within strings or parentheses don't separate arguments
+        let mut paren_depth = 0;
         let end_tok = loop {
             match self.peek() {
-                Some('"') => inside_string = !inside_string,
                 Some(';') if !inside_string => self.discard_comment(),
-                Some(',') => break Some(TokType::Comma),
+                Some(',') if !inside_string && paren_depth == 0 => break Some(TokType::Comma),
                 Some('\r') => break Some(TokType::Newline),
                 Some('\n') => break Some(TokType::Newline),
                 None => break None,
                 Some('\\') => {
                     self.next();
                     match self.next() {
-                        Some(',') => {
+                        // Escaped so that they don't count as separators nor grouping
+                        Some(c @ (',' | '(' | ')')) => {
                             end = Some(self.loc.clone());
-                            arg.push(',');
+                            arg.push(c);
                         }
                         Some(c) if Self::begins_line_cont(c) => self.read_line_cont(),
                         None => {
-                            (self.diagnose)(Diagnostic::Error(
-                                AssemblerError::IllegalEscapeEOF.into(),
-                            ));
+                            self.report_error(AssemblerError::IllegalEscapeEOF);
                             end = Some(self.loc.clone());
                             arg.push('\\');
                         }
@@ -921,6 +1918,12 @@ impl<'a> Lexer<'a> {
                 }
                 Some(&c) => {
                     self.next();
+                    match c {
+                        '"' => inside_string = !inside_string,
+                        '(' if !inside_string => paren_depth += 1,
+                        ')' if !inside_string && paren_depth != 0 => paren_depth -= 1,
+                        _ => (),
+                    }
                     if !Self::is_whitespace(c) {
                         end = Some(self.loc.clone());
                     }
@@ -929,7 +1932,7 @@ impl<'a> Lexer<'a> {
             }
         };
 
-        if arg.len() == 0 {
+        if arg.is_empty() {
             self.next();
             Ok(end_tok.map(|tok| (begin, tok, self.loc.clone())))
         } else {
@@ -942,14 +1945,107 @@ impl<'a> Lexer<'a> {
     }
 }
 
-impl Iterator for Lexer<'_> {
-    type Item = Result<Token, AssemblerError>;
+impl Iterator for Lexer<'_, '_> {
+    type Item = Result<Token, SpannedError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match &self.state.borrow().state {
-            State::Normal => self.next_normal_tok(),
-            State::Raw => self.next_raw_tok(),
+        if self.assembler.is_halted() && self.at_line_start {
+            return None;
+        }
+
+        let (macro_def, loop_def, rept_def, loop_break, skip, expansion) = {
+            let mut state = self.state.borrow_mut();
+            (
+                state.macro_def.take(),
+                state.loop_def.take(),
+                state.rept_def.take(),
+                std::mem::take(&mut state.loop_break),
+                state.skip.take(),
+                state.expansion.take(),
+            )
+        };
+        if let Some(name) = macro_def {
+            self.capture_macro(name);
+        }
+        if let Some(run) = loop_def {
+            self.capture_loop(run);
+        }
+        if let Some((count, var)) = rept_def {
+            self.capture_rept(count, var);
+        }
+        if loop_break {
+            self.break_loop();
+        }
+        let mut branch = skip.and_then(|skip| self.skip_conditional(skip));
+        if let Some(expansion) = expansion {
+            self.begin_expansion(expansion);
         }
-        .transpose()
+        loop {
+            let tok = match branch.take() {
+                Some(tok) => Ok(Some(tok)),
+                None => match &self.state.borrow().state {
+                    State::Normal => self.next_normal_tok(),
+                    State::Raw => self.next_raw_tok(),
+                },
+            };
+            // Errors are reported, but lexing goes on, so that the parser can report more errors
+            match tok {
+                // The last line may lack a newline, but the parser still expects one to end it
+                Ok(None) if !self.at_line_start => {
+                    self.at_line_start = true;
+                    self.state.borrow_mut().set_state(State::Normal);
+                    let loc = self.loc.clone();
+                    return Some(Ok((loc.clone(), TokType::Newline, loc)));
+                }
+                Ok(tok) => {
+                    // `OPT`'s (and `PUSHO`'s) arguments are raw, but the parser would only switch
+                    // modes after having lexed the first one as lookahead, so switch right away
+                    if let Some((_, TokType::Opt | TokType::Pusho, _)) = tok {
+                        self.state.borrow_mut().set_state(State::Raw);
+                    }
+                    if let Some((_, TokType::While, _)) = tok {
+                        self.loop_header = Some(String::new());
+                    }
+                    self.equs_depth = 0;
+                    match tok {
+                        Some((_, TokType::Def | TokType::Redef | TokType::For, _)) => {
+                            self.equs_mode = EqusMode::KeepNextIdent
+                        }
+                        Some((_, TokType::Purge, _)) => self.equs_mode = EqusMode::KeepLine,
+                        Some((_, TokType::Newline, _)) => self.equs_mode = EqusMode::Expand,
+                        _ => (),
+                    }
+                    self.at_line_start = matches!(tok, Some((_, TokType::Newline, _)));
+                    // Raw arguments never span several lines, even if the parser gave up on them
+                    if self.at_line_start {
+                        self.state.borrow_mut().set_state(State::Normal);
+                    }
+                    return tok.map(Ok);
+                }
+                Err(err) => self
+                    .assembler
+                    .report_error(err.at(self.tok_begin.clone(), self.loc.clone())),
+            }
+        }
+    }
+}
+
+// Reads a whole string as a single number, the way it would be lexed, e.g. for `-D` values
+// A leading `-` negates it
+pub fn parse_number(string: &str, assembler: &Assembler) -> Option<i32> {
+    let (negative, digits) = match string.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, string),
+    };
+    let state = RefCell::new(Lexer::new_state());
+    let mut lexer = Lexer::new(digits.chars(), &state, assembler);
+    let first = lexer.next()?;
+    let val = match lexer.read_number(first) {
+        Ok(TokType::Num(val)) => val,
+        _ => return None,
+    };
+    if lexer.peek().is_some() {
+        return None;
     }
+    Some(if negative { val.wrapping_neg() } else { val })
 }
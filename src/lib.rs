@@ -1,28 +1,59 @@
+mod charmap;
+mod deps;
 mod expression;
 mod instruction;
 mod lexer;
+mod listing;
+mod object;
+mod options;
+mod rom;
 mod section;
 mod symbol;
-use crate::lexer::{Lexer, Location, LocationSpan, TokType};
+mod symfile;
+use crate::charmap::Charmap;
+use crate::expression::{BinOp, Expression};
+use crate::lexer::{advance_col, CharReader, Lexer, LocationSpan, LoopVar};
+use crate::listing::Listing;
 use crate::parser::AsmParser;
-use crate::symbol::Symbol;
+use crate::section::{PatchType, Section};
 use lalrpop_util::lalrpop_mod;
-use std::cell::{Ref, RefCell};
-use std::collections::HashMap;
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
 use std::fmt::{self, Display, Formatter};
-use std::io::{self, Read};
-use std::rc::{Rc, Weak};
+use std::io::{self, Read, Write};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
-lalrpop_mod!(parser);
+pub use crate::lexer::{suggest_mnemonic, Location, TokType};
+pub use crate::options::AssemblerOptions;
+pub use crate::section::{SectionInfo, Type as SectionType};
+pub use crate::symbol::Symbol;
 
-type ParseError = lalrpop_util::ParseError<Location, TokType, AssemblerError>;
+lalrpop_mod!(
+    #[allow(clippy::all)]
+    parser
+);
+
+type ParseError = lalrpop_util::ParseError<Location, TokType, SpannedError>;
 
 #[derive(Debug)]
 pub struct Error {
     err: ParseError,
 }
 
-fn write_expected_tokens(fmt: &mut Formatter, expected: &Vec<String>) -> Result<(), fmt::Error> {
+// What kind of error an `Error` is, mirroring LALRPOP's, so that tools don't have to depend on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    InvalidToken,
+    UnexpectedEof,
+    UnexpectedToken,
+    ExtraToken,
+    User,
+}
+
+fn write_expected_tokens(fmt: &mut Formatter, expected: &[String]) -> Result<(), fmt::Error> {
     let mut items = expected.iter();
     write!(fmt, "{}", items.next().unwrap())?;
 
@@ -38,6 +69,118 @@ fn write_expected_tokens(fmt: &mut Formatter, expected: &Vec<String>) -> Result<
     Ok(())
 }
 
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        use lalrpop_util::ParseError::*;
+
+        match &self.err {
+            InvalidToken { .. } => ErrorKind::InvalidToken,
+            UnrecognizedEOF { .. } => ErrorKind::UnexpectedEof,
+            UnrecognizedToken { .. } => ErrorKind::UnexpectedToken,
+            ExtraToken { .. } => ErrorKind::ExtraToken,
+            User { .. } => ErrorKind::User,
+        }
+    }
+
+    // Where the error begins, if known
+    pub fn location(&self) -> Option<Location> {
+        self.span().map(|(begin, _)| begin.clone())
+    }
+
+    // Empty unless the parser was expecting something else
+    pub fn expected_tokens(&self) -> Vec<String> {
+        use lalrpop_util::ParseError::*;
+
+        match &self.err {
+            UnrecognizedEOF { expected, .. } | UnrecognizedToken { expected, .. } => {
+                expected.clone()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn span(&self) -> Option<(&Location, &Location)> {
+        use lalrpop_util::ParseError::*;
+
+        match &self.err {
+            InvalidToken { location } | UnrecognizedEOF { location, .. } => {
+                Some((location, location))
+            }
+            UnrecognizedToken {
+                token: (begin, _, end),
+                ..
+            }
+            | ExtraToken {
+                token: (begin, _, end),
+            } => Some((begin, end)),
+            User { error } => error.span.as_ref().map(|(begin, end)| (begin, end)),
+        }
+    }
+
+    // Renders the error followed by the offending source line, with the span underlined
+    pub fn render_with_source(&self, src: &str) -> String {
+        self.render_with_source_tabs(src, 8)
+    }
+
+    // Same as `render_with_source`, for errors reported with a different tab width
+    pub fn render_with_source_tabs(&self, src: &str, tab_width: u8) -> String {
+        let mut out = format!("error: {}\n", self);
+        let (begin, end) = match self.span() {
+            Some(span) => span,
+            None => return out,
+        };
+        let line = match src.lines().nth(begin.line_no() as usize - 1) {
+            Some(line) => line,
+            None => return out,
+        };
+
+        // Spans ending at the very beginning of a line (e.g. newlines) don't really cover it
+        let continues = end.line_no() > begin.line_no() + 1
+            || (end.line_no() > begin.line_no() && end.col_no() > 1);
+        let line_len = line.chars().count() as u32 + 1;
+        let begin_col = char_col(line, begin.col_no(), tab_width.max(1)).min(line_len);
+        let end_col = if end.line_no() == begin.line_no() {
+            char_col(line, end.col_no(), tab_width.max(1))
+        } else {
+            line_len
+        };
+
+        // Keep tabs, so that the carets line up with the source
+        let padding: String = line
+            .chars()
+            .take(begin_col as usize - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let carets = "^".repeat(end_col.saturating_sub(begin_col).max(1) as usize);
+
+        let line_no = begin.line_no().to_string();
+        let gutter = " ".repeat(line_no.len());
+        out.push_str(&format!("{} |\n", gutter));
+        out.push_str(&format!("{} | {}\n", line_no, line));
+        out.push_str(&format!("{} | {}{}", gutter, padding, carets));
+        if continues {
+            out.push_str(&format!(" (continues until line {})", end.line_no()));
+        }
+        out.push('\n');
+        out
+    }
+}
+
+// Converts a column as reported (tabs expanded) back to a character position in the line
+fn char_col(line: &str, col: u32, tab_width: u8) -> u32 {
+    let mut visual = 1;
+    let mut chars = 1;
+    for c in line.chars() {
+        if visual >= col {
+            break;
+        }
+        visual = advance_col(visual, c, tab_width);
+        chars += 1;
+    }
+    // Columns past the end of the line (e.g. its newline) are kept as-is
+    chars + col.saturating_sub(visual)
+}
+
 impl Display for Error {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
         use lalrpop_util::ParseError::*;
@@ -83,13 +226,103 @@ impl From<ParseError> for Error {
 
 impl From<AssemblerError> for Error {
     fn from(err: AssemblerError) -> Self {
+        Self {
+            err: SpannedError::from(err).into(),
+        }
+    }
+}
+
+impl From<SpannedError> for Error {
+    fn from(err: SpannedError) -> Self {
         Self { err: err.into() }
     }
 }
 
 #[derive(Debug)]
 pub enum Warning {
-    //
+    AssertFailure(Option<String>),
+    CaseCollision { existing: String, new: String },
+    CodeBeforeSection,
+    CouldBeJr { distance: i32 },
+    EmptyEntity,
+    ImplicitA,
+    LargeJr(i32),
+    ObsoleteSyntax(String),
+    RsOverflow(i32),
+    Truncated8(i32),
+    // The integer part, and how many bits it may have
+    TruncatedFixedPoint(i32, u8),
+    UnusedSymbol(String),
+    UserWarn(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WarningClass {
+    Assert,
+    CaseCollision,
+    CodeBeforeSection,
+    CouldBeJr,
+    EmptyEntity,
+    ImplicitA,
+    LargeJr,
+    Obsolete,
+    Overflow,
+    Truncation,
+    UnusedSymbol,
+    User,
+}
+
+impl Warning {
+    pub fn class(&self) -> WarningClass {
+        match self {
+            Self::AssertFailure(_) => WarningClass::Assert,
+            Self::CaseCollision { .. } => WarningClass::CaseCollision,
+            Self::CodeBeforeSection => WarningClass::CodeBeforeSection,
+            Self::CouldBeJr { .. } => WarningClass::CouldBeJr,
+            Self::EmptyEntity => WarningClass::EmptyEntity,
+            Self::ImplicitA => WarningClass::ImplicitA,
+            Self::LargeJr(_) => WarningClass::LargeJr,
+            Self::ObsoleteSyntax(_) => WarningClass::Obsolete,
+            Self::RsOverflow(_) => WarningClass::Overflow,
+            Self::Truncated8(_) | Self::TruncatedFixedPoint(..) => WarningClass::Truncation,
+            Self::UnusedSymbol(_) => WarningClass::UnusedSymbol,
+            Self::UserWarn(_) => WarningClass::User,
+        }
+    }
+}
+
+impl Display for Warning {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Self::AssertFailure(Some(s)) => write!(fmt, "Assertion failure: {}", s),
+            Self::AssertFailure(None) => write!(fmt, "Assertion failure"),
+            Self::CaseCollision { existing, new } => write!(
+                fmt,
+                "Symbol \"{}\" only differs from \"{}\" by case",
+                new, existing
+            ),
+            Self::CodeBeforeSection => write!(
+                fmt,
+                "Code before the first SECTION is placed in an implicit ROM0 section"
+            ),
+            Self::CouldBeJr { distance } => {
+                write!(fmt, "jp could be a jr (offset {})", distance)
+            }
+            Self::EmptyEntity => write!(fmt, "Empty entity"),
+            Self::ImplicitA => write!(fmt, "Accumulator operand `a` is left implicit"),
+            Self::LargeJr(ofs) => write!(fmt, "jr offset {} is out of range", ofs),
+            Self::ObsoleteSyntax(s) => write!(fmt, "{}", s),
+            Self::RsOverflow(val) => write!(fmt, "_RS overflowed, clamped to {}", val),
+            Self::Truncated8(val) => write!(fmt, "Expression {} must be 8-bit", val),
+            Self::TruncatedFixedPoint(val, bits) => write!(
+                fmt,
+                "Integer part {} of fixed-point constant must be {}-bit",
+                val, bits
+            ),
+            Self::UnusedSymbol(name) => write!(fmt, "Symbol \"{}\" is never referenced", name),
+            Self::UserWarn(msg) => write!(fmt, "{}", msg),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -98,6 +331,7 @@ pub enum AssemblerError {
     BadInterpFmt(String),
     CharAfterLineCont(char),
     GarbageChar(char),
+    EmptyCPrefix(char),
     EmptyFract,
     EmptyGfx,
     EmptyHex,
@@ -107,21 +341,139 @@ pub enum AssemblerError {
     IllegalEscape(char),
     IllegalEscapeEOF,
     IllegalInterpChar(char),
+    InterpNotConstant(String),
+    InterpUndefined(String),
     LineContEOF,
+    MalformedNumber,
     MultipleInterpFmt,
     UntermInterp,
+    UntermChar,
     UntermString,
 
+    // I/O errors
+    FileRead(String, io::Error),
+    IncbinOutOfBounds {
+        path: String,
+        file_len: usize,
+        requested: usize,
+    },
+    NegativeIncbin(i32),
+    PrintWrite(io::Error),
+
     // Logic errors
     AssertFailure(Option<String>),
+    PromotedWarning(Warning),
+    TooManyErrors(usize),
+    HadErrors(usize),
+    BadLdOperands,
+    BadHighCBase(String),
+    BadHramAddr(i32),
+    BadRstTarget(i32),
     LdHLHL,
+    // The instruction as written, and an equivalent sequence if there is one
+    NoSuchInstruction(String, Option<String>),
     LocalInMainScope(String),
+    SpOffsetRange(i32),
+    JrOffsetRange(i32),
+    Recursion(usize),
+    UserFail(String),
+    WhileLimitExceeded(usize),
+
+    // Macro errors
+    BreakOutsideLoop,
+    ForStepZero,
+    MacroArgOutsideMacro,
+    NegativeRept(i32),
+    NoSuchMacroArg(usize),
+    // Also holds the instruction it may be a typo of
+    NotAMacro(String, Option<&'static str>),
+    // `ELIF`, `ELSE` or `ENDC` without an `IF`
+    OutsideConditional(&'static str),
+    ShiftOutsideMacro,
+    ShiftPastEnd,
+    UniqueIdOutsideMacro,
+    UntermConditional,
+    UntermLoop,
+    UntermMacro,
 
     // Expression errors
-    ExprNotConstant,
+    DivByZero,
+    ExprNotConstant(String),
+    ValueOutOfRange8(i32),
+    ValueOutOfRange16(i32),
 
     // Symbol errors
+    BadSymbolDef(String),
+    IllegalSymbolName(String),
+    PurgeBuiltin(String),
+    PurgeReferenced(String),
+    RedefEquAsSet(String),
+    RedefLabel(String),
+    RedefSetAsEqu(String),
+    StringSymbolInExpr(String),
+    StringSymbolForwardRef(String),
     SymbolRedef,
+    SymbolUndefined(String),
+
+    // Section errors
+    BadAlign(i32),
+    BadAlignOffset(i32),
+    BadBank(i32, section::Type),
+    BadOrg(i32, section::Type),
+    BadSectionType(String),
+    DataInRamSection(String),
+    EndlWithoutLoad,
+    NeedsLinking(String),
+    NegativeDs(i32),
+    NestedLoad,
+    NoSection,
+    OrgAlignMismatch,
+    PcOutsideSection,
+    PopEmptySectionStack,
+    SectionOverflow(String),
+    SectionOverlap(String, String),
+    SectionRedef(String),
+    UnbankedSection(section::Type),
+
+    // Charmap errors
+    CharmapRedef(String),
+    MultiCharConstant(String),
+    NoSuchCharmap(String),
+    PopEmptyCharmapStack,
+
+    // Option errors
+    BadOption(String),
+    PopEmptyOptionStack,
+}
+
+#[derive(Debug)]
+pub struct SpannedError {
+    err: AssemblerError,
+    span: Option<(Location, Location)>,
+}
+
+impl AssemblerError {
+    pub fn at(self, begin: Location, end: Location) -> SpannedError {
+        SpannedError {
+            err: self,
+            span: Some((begin, end)),
+        }
+    }
+}
+
+impl From<AssemblerError> for SpannedError {
+    fn from(err: AssemblerError) -> Self {
+        Self { err, span: None }
+    }
+}
+
+impl Display for SpannedError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match &self.span {
+            Some((begin, end)) => write!(fmt, "{} at {}", self.err, LocationSpan::new(begin, end)),
+            None => self.err.fmt(fmt),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -130,7 +482,11 @@ pub enum Diagnostic {
     Error(Error),
 }
 
-pub type DiagCallback = dyn Fn(Diagnostic);
+// Returning `Break` stops the assembly after the current line
+pub type DiagCallback<'a> = dyn Fn(Diagnostic) -> ControlFlow<()> + 'a;
+
+// Returns the contents of an included file, or `None` to read it from the filesystem instead
+pub type IncludeResolver<'a> = dyn Fn(&str) -> Option<Vec<u8>> + 'a;
 
 impl Display for AssemblerError {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
@@ -142,6 +498,7 @@ impl Display for AssemblerError {
                 c
             ),
             Self::GarbageChar(c) => write!(fmt, "Garbage char '{}'", c),
+            Self::EmptyCPrefix(c) => write!(fmt, "Invalid constant, no digits after '0{}'", c),
             Self::EmptyFract => write!(fmt, "Invalid fixed-point constant, no digits after '.'"),
             Self::EmptyGfx => write!(fmt, "Invalid gfx constant, no digits after '`'"),
             Self::EmptyHex => write!(fmt, "Invalid hex constant, no digits after '$'"),
@@ -151,23 +508,218 @@ impl Display for AssemblerError {
             Self::IllegalEscape(c) => write!(fmt, "Illegal character escape '{}'", c),
             Self::IllegalEscapeEOF => write!(fmt, "Illegal character escape at end of input"),
             Self::IllegalInterpChar(c) => write!(fmt, "Illegal character '{}' in interpolation", c),
+            Self::InterpNotConstant(name) => {
+                write!(fmt, "Interpolated symbol \"{}\" is not constant", name)
+            }
+            Self::InterpUndefined(name) => {
+                write!(fmt, "Interpolated symbol \"{}\" does not exist", name)
+            }
+            Self::MalformedNumber => {
+                write!(fmt, "Digit separators must be placed between digits")
+            }
             Self::LineContEOF => write!(fmt, "Line continuation at end of file"),
             Self::MultipleInterpFmt => write!(fmt, "Multiple interpolation formats"),
             Self::UntermInterp => write!(fmt, "Unterminated interpolation"),
+            Self::UntermChar => write!(fmt, "Unterminated character constant"),
             Self::UntermString => write!(fmt, "Unterminated string"),
 
+            Self::FileRead(path, err) => write!(fmt, "Failed to read \"{}\": {}", path, err),
+            Self::IncbinOutOfBounds {
+                path,
+                file_len,
+                requested,
+            } => write!(
+                fmt,
+                "INCBIN range ends at byte {}, but \"{}\" is only {} bytes long",
+                requested, path, file_len
+            ),
+            Self::NegativeIncbin(val) => write!(fmt, "INCBIN offset or length {} is negative", val),
+            Self::PrintWrite(err) => write!(fmt, "Failed to print: {}", err),
+
             Self::AssertFailure(Some(s)) => write!(fmt, "Assertion failure: {}", s),
             Self::AssertFailure(None) => write!(fmt, "Assertion failure"),
+            Self::PromotedWarning(warning) => write!(fmt, "{} (treated as error)", warning),
+            Self::TooManyErrors(count) => write!(fmt, "Assembly aborted after {} errors", count),
+            Self::HadErrors(count) => {
+                write!(fmt, "Refusing to write output after {} errors", count)
+            }
+            Self::BadLdOperands => write!(fmt, "Invalid operands for ld"),
+            Self::BadHighCBase(base) => {
+                write!(fmt, "Only $FF00 can be added to c, not `{}`", base)
+            }
+            Self::BadHramAddr(addr) => {
+                write!(fmt, "Address ${:04X} is not in HRAM ($FF00-$FFFF)", addr)
+            }
+            Self::BadRstTarget(addr) => write!(fmt, "Invalid rst target {}", addr),
             Self::LdHLHL => write!(fmt, "ld [hl], [hl] is not a valid instruction"),
+            Self::NoSuchInstruction(instr, None) => {
+                write!(fmt, "No such instruction \"{}\"", instr)
+            }
+            Self::NoSuchInstruction(instr, Some(seq)) => {
+                write!(
+                    fmt,
+                    "No such instruction \"{}\"; use \"{}\" instead",
+                    instr, seq
+                )
+            }
             Self::LocalInMainScope(name) => write!(fmt, "Local symbol \"{}\" in main scope", name),
+            Self::Recursion(depth) => write!(fmt, "Recursion limit ({}) exceeded", depth),
+            Self::SpOffsetRange(ofs) => {
+                write!(
+                    fmt,
+                    "Stack pointer offset {} must be between -128 and 127",
+                    ofs
+                )
+            }
+            Self::JrOffsetRange(ofs) => {
+                write!(fmt, "jr offset {} must be between -128 and 127", ofs)
+            }
+            Self::UserFail(msg) => write!(fmt, "{}", msg),
+            Self::WhileLimitExceeded(count) => {
+                write!(fmt, "WHILE loop still running after {} iterations", count)
+            }
+
+            Self::BreakOutsideLoop => write!(fmt, "BREAK can only be used inside a loop"),
+            Self::ForStepZero => write!(fmt, "FOR cannot have a step value of 0"),
+            Self::MacroArgOutsideMacro => write!(fmt, "Macro argument used outside of a macro"),
+            Self::NegativeRept(count) => write!(fmt, "REPT count {} is negative", count),
+            Self::NoSuchMacroArg(index) => write!(fmt, "Macro argument \\{} is not defined", index),
+            Self::NotAMacro(name, None) => write!(fmt, "\"{}\" is not a macro", name),
+            Self::NotAMacro(name, Some(mnemonic)) => {
+                write!(
+                    fmt,
+                    "\"{}\" is not a macro; did you mean \"{}\"?",
+                    name, mnemonic
+                )
+            }
+            Self::OutsideConditional(keyword) => {
+                write!(fmt, "Found {} outside of an IF construct", keyword)
+            }
+            Self::ShiftOutsideMacro => {
+                write!(fmt, "Cannot shift macro arguments outside of a macro")
+            }
+            Self::ShiftPastEnd => write!(fmt, "Cannot shift macro arguments past their end"),
+            Self::UniqueIdOutsideMacro => write!(fmt, "\\@ used outside of a macro or loop"),
+            Self::UntermConditional => write!(fmt, "Unterminated IF construct (missing ENDC)"),
+            Self::UntermLoop => write!(fmt, "Unterminated loop (missing ENDR)"),
+            Self::UntermMacro => write!(fmt, "Unterminated macro definition"),
 
-            Self::ExprNotConstant => write!(fmt, "Expression is not constant"),
+            Self::DivByZero => write!(fmt, "Division by zero"),
+            Self::ExprNotConstant(expr) => write!(fmt, "Expression `{}` is not constant", expr),
+            Self::ValueOutOfRange8(val) => write!(fmt, "Value {} doesn't fit in 8 bits", val),
+            Self::ValueOutOfRange16(val) => write!(fmt, "Value {} doesn't fit in 16 bits", val),
 
+            Self::BadSymbolDef(spec) => write!(fmt, "Invalid symbol definition \"{}\"", spec),
+            Self::IllegalSymbolName(name) => write!(fmt, "Illegal symbol name \"{}\"", name),
+            Self::PurgeBuiltin(name) => {
+                write!(fmt, "Built-in symbol \"{}\" cannot be purged", name)
+            }
+            Self::PurgeReferenced(name) => write!(
+                fmt,
+                "Symbol \"{}\" cannot be purged, as the object file refers to it",
+                name
+            ),
+            Self::RedefEquAsSet(name) => {
+                write!(fmt, "Cannot redefine EQU symbol \"{}\" as SET", name)
+            }
+            Self::RedefLabel(name) => write!(fmt, "Label \"{}\" is already defined", name),
+            Self::RedefSetAsEqu(name) => {
+                write!(fmt, "Cannot redefine SET symbol \"{}\" as EQU", name)
+            }
+            Self::StringSymbolInExpr(name) => {
+                write!(fmt, "Symbol \"{}\" is a string, not a number", name)
+            }
+            Self::StringSymbolForwardRef(name) => write!(
+                fmt,
+                "Symbol \"{}\" was used as a number before being defined as a string",
+                name
+            ),
             Self::SymbolRedef => write!(fmt, "Redefined symbol"),
+            Self::SymbolUndefined(name) => write!(fmt, "Symbol \"{}\" is not defined", name),
+
+            Self::BadAlign(bits) => write!(fmt, "Alignment {} must be between 0 and 16", bits),
+            Self::BadAlignOffset(ofs) => {
+                write!(
+                    fmt,
+                    "Alignment offset {} must be smaller than the alignment",
+                    ofs
+                )
+            }
+            Self::BadBank(bank, sect_type) => {
+                write!(
+                    fmt,
+                    "Bank {} is out of range for {} sections",
+                    bank, sect_type
+                )
+            }
+            Self::BadOrg(org, sect_type) => {
+                let range = sect_type.address_range();
+                write!(
+                    fmt,
+                    "Address ${:X} is outside of {} (${:04X}-${:04X})",
+                    org,
+                    sect_type,
+                    range.start(),
+                    range.end()
+                )
+            }
+            Self::BadSectionType(name) => write!(fmt, "Unknown section type \"{}\"", name),
+            Self::DataInRamSection(name) => write!(
+                fmt,
+                "Section \"{}\" cannot contain code or data (not ROM0 or ROMX)",
+                name
+            ),
+            Self::EndlWithoutLoad => write!(fmt, "Found ENDL outside of a LOAD block"),
+            Self::NeedsLinking(name) => {
+                write!(fmt, "Section \"{}\" can only be placed by linking", name)
+            }
+            Self::NegativeDs(len) => write!(fmt, "DS length {} is negative", len),
+            Self::NestedLoad => write!(fmt, "LOAD blocks cannot be nested"),
+            Self::NoSection => write!(fmt, "Code or data outside of a section"),
+            Self::OrgAlignMismatch => write!(fmt, "Section address doesn't match its alignment"),
+            Self::PcOutsideSection => write!(fmt, "PC symbol \"@\" used outside of a section"),
+            Self::PopEmptySectionStack => write!(fmt, "No entries in the section stack"),
+            Self::SectionOverflow(name) => {
+                write!(fmt, "Section \"{}\" doesn't fit in its memory region", name)
+            }
+            Self::SectionOverlap(first, second) => {
+                write!(fmt, "Sections \"{}\" and \"{}\" overlap", first, second)
+            }
+            Self::SectionRedef(name) => write!(fmt, "Section \"{}\" already exists", name),
+            Self::UnbankedSection(sect_type) => write!(
+                fmt,
+                "BANK is only allowed for ROMX, VRAM, SRAM and WRAMX sections, not {}",
+                sect_type
+            ),
+
+            Self::CharmapRedef(name) => write!(fmt, "Charmap \"{}\" already exists", name),
+            Self::MultiCharConstant(s) => {
+                write!(fmt, "Character constant '{}' is more than one character", s)
+            }
+            Self::NoSuchCharmap(name) => write!(fmt, "Charmap \"{}\" doesn't exist", name),
+            Self::PopEmptyCharmapStack => write!(fmt, "No entries in the charmap stack"),
+
+            Self::BadOption(spec) => write!(fmt, "Invalid option \"{}\"", spec),
+            Self::PopEmptyOptionStack => write!(fmt, "No entries in the option stack"),
         }
     }
 }
 
+// How `PRINT` formats numbers; the obsolete `PRINTx` directives each use a different one
+#[derive(Debug, Clone, Copy)]
+pub enum PrintFmt {
+    Hex,
+    Unsigned,
+    Signed,
+    Fixed,
+}
+
+#[derive(Debug)]
+pub enum PrintArg {
+    Str(String),
+    Num(Expression, PrintFmt),
+}
+
 #[derive(Debug)]
 pub enum AssertType {
     Warn,
@@ -175,116 +727,1416 @@ pub enum AssertType {
     Fatal,
 }
 
+// An assertion that couldn't be checked at assembly time, left for the linker
+#[derive(Debug)]
+struct DeferredAssertion {
+    assert_type: AssertType,
+    expr: Expression,
+    msg: Option<String>,
+    pc: Option<(usize, u16)>,
+    location: Option<Location>,
+}
+
+// The arguments of a macro being expanded
+struct MacroFrame {
+    args: Vec<String>,
+    // How many arguments `SHIFT` has discarded
+    shift: usize,
+    // What `_NARG` was before the macro was entered, restored once it ends
+    saved_narg: Option<Symbol>,
+}
+
+// What `PUSHS` saves, and `POPS` restores
+struct SectionFrame {
+    section: Option<usize>,
+    load_section: Option<usize>,
+    scope: Option<Rc<String>>,
+}
+
 pub struct Assembler<'a> {
-    symbols: RefCell<HashMap<Rc<String>, Symbol>>,
-    sym_scope: RefCell<Option<Weak<Symbol>>>,
+    // Sorted, so that iterating over symbols (e.g. for output files) is reproducible
+    symbols: RefCell<BTreeMap<Rc<String>, Symbol>>,
+    sym_scope: RefCell<Option<Rc<String>>>,
+    // Symbols referenced before being defined
+    forward_refs: RefCell<HashSet<Rc<String>>>,
+    // Symbol names by their lowercase form, to spot names that only differ by case
+    folded_names: RefCell<HashMap<String, Vec<Rc<String>>>>,
+    sections: RefCell<Vec<Section>>,
+    cur_section: Cell<Option<usize>>,
+    // Section that labels are defined in, inside of a `LOAD` block
+    load_section: Cell<Option<usize>>,
+    section_stack: RefCell<Vec<SectionFrame>>,
+    macro_frames: RefCell<Vec<MacroFrame>>,
+    // Counts macro invocations and loop iterations, to make `\@` unique
+    unique_ids: Cell<u32>,
+    // Those of the macro invocations and loop iterations being expanded, innermost last
+    expansion_ids: RefCell<Vec<u32>>,
+    charmaps: RefCell<HashMap<String, Charmap>>,
+    cur_charmap: RefCell<String>,
+    charmap_stack: RefCell<Vec<String>>,
+    deps: RefCell<Vec<String>>,
+    assertions: RefCell<Vec<DeferredAssertion>>,
+    // What each assembly starts with, before any `OPT`
+    base_options: AssemblerOptions,
+    options: RefCell<AssemblerOptions>,
+    option_stack: RefCell<Vec<AssemblerOptions>>,
+
+    cur_location: RefCell<Option<Location>>,
+    error_count: Cell<usize>,
+    halted: Cell<bool>,
+    // Set when the diagnostic callback asks to stop, after which it isn't called anymore
+    aborted: Cell<bool>,
+
+    // Configuration
+    include_dirs: Vec<PathBuf>,
+    // Consulted before the filesystem, e.g. to serve files from memory
+    include_resolver: Option<Box<IncludeResolver<'a>>>,
+    max_recursion_depth: usize,
+    max_errors: usize,
+    tab_width: u8,
+    predefined: RefCell<Vec<Symbol>>,
+    enabled_warnings: HashSet<WarningClass>,
+    error_warnings: HashSet<WarningClass>,
+    warnings_as_errors: bool,
+    // Whether the object file is written even if assembly failed
+    partial_output: bool,
+
+    // Outputs
+    listing: Option<RefCell<Listing<'a>>>,
+    print_out: RefCell<Box<dyn Write + 'a>>,
 
     // Callbacks
-    diagnose: &'a DiagCallback,
+    diagnose: &'a DiagCallback<'a>,
 }
 
 impl<'a> Assembler<'a> {
     // === Contructor ===
 
-    pub fn new(diagnose: &'a DiagCallback) -> Self {
-        Self {
-            symbols: RefCell::new(HashMap::new()),
+    pub fn new(diagnose: &'a DiagCallback<'a>) -> Self {
+        let asm = Self {
+            symbols: RefCell::new(BTreeMap::new()),
             sym_scope: RefCell::new(None),
+            forward_refs: RefCell::new(HashSet::new()),
+            folded_names: RefCell::new(HashMap::new()),
+            sections: RefCell::new(Vec::new()),
+            cur_section: Cell::new(None),
+            load_section: Cell::new(None),
+            section_stack: RefCell::new(Vec::new()),
+            macro_frames: RefCell::new(Vec::new()),
+            unique_ids: Cell::new(0),
+            expansion_ids: RefCell::new(Vec::new()),
+            charmaps: RefCell::new(HashMap::new()),
+            cur_charmap: RefCell::new(String::new()),
+            charmap_stack: RefCell::new(Vec::new()),
+            deps: RefCell::new(Vec::new()),
+            assertions: RefCell::new(Vec::new()),
+            base_options: AssemblerOptions::default(),
+            options: RefCell::new(AssemblerOptions::default()),
+            option_stack: RefCell::new(Vec::new()),
+            cur_location: RefCell::new(None),
+            error_count: Cell::new(0),
+            halted: Cell::new(false),
+            aborted: Cell::new(false),
+
+            include_dirs: Vec::new(),
+            include_resolver: None,
+            max_recursion_depth: 64,
+            max_errors: 100,
+            tab_width: 8,
+            predefined: RefCell::new(Vec::new()),
+            // `CaseCollision`, `CouldBeJr`, `EmptyEntity` and `ImplicitA` are rather pedantic, so
+            // they're disabled by default
+            enabled_warnings: [
+                WarningClass::Assert,
+                WarningClass::CodeBeforeSection,
+                WarningClass::LargeJr,
+                WarningClass::Obsolete,
+                WarningClass::Overflow,
+                WarningClass::Truncation,
+                WarningClass::UnusedSymbol,
+                WarningClass::User,
+            ]
+            .iter()
+            .copied()
+            .collect(),
+            error_warnings: HashSet::new(),
+            warnings_as_errors: false,
+            partial_output: false,
+
+            listing: None,
+            print_out: RefCell::new(Box::new(io::stdout())),
 
             diagnose,
+        };
+        // Sets up `_RS` and the main charmap, so that the API and `assemble_append` can be used
+        // right away
+        asm.reset();
+        asm
+    }
+
+    // === Configuration ===
+
+    pub fn add_include_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.include_dirs.push(dir.into());
+    }
+
+    // Files the resolver returns `None` for are looked up as usual
+    pub fn set_include_resolver(&mut self, resolver: impl Fn(&str) -> Option<Vec<u8>> + 'a) {
+        self.include_resolver = Some(Box::new(resolver));
+    }
+
+    // Limits how deeply expansions may nest, so that pathological input can't overflow the stack
+    pub fn set_max_recursion_depth(&mut self, depth: usize) {
+        self.max_recursion_depth = depth;
+    }
+
+    pub(crate) fn max_recursion_depth(&self) -> usize {
+        self.max_recursion_depth
+    }
+
+    // Assembly stops after this many errors, or never if 0
+    pub fn set_max_errors(&mut self, max_errors: usize) {
+        self.max_errors = max_errors;
+    }
+
+    // How many columns a tab advances to, so that reported columns match editors
+    pub fn set_tab_width(&mut self, width: u8) {
+        self.tab_width = width.max(1);
+    }
+
+    pub(crate) fn tab_width(&self) -> u8 {
+        self.tab_width
+    }
+
+    // Lets `write_object` emit whatever was assembled despite errors, e.g. for IDEs
+    // Such objects are flagged in their header, so that the linker rejects them
+    pub fn allow_partial_output(&mut self, enabled: bool) {
+        self.partial_output = enabled;
+    }
+
+    pub(crate) fn partial_output(&self) -> bool {
+        self.partial_output
+    }
+
+    // What `OPT` changes, e.g. to emulate RGBASM's command-line flags
+    pub fn set_options(&mut self, options: AssemblerOptions) {
+        self.options.replace(options.clone());
+        self.base_options = options;
+    }
+
+    // Defines a symbol now and before each assembly, from a `NAME[=VALUE]` spec like RGBASM's `-D`
+    // Values that aren't numbers define an EQUS, and a bare name is set to 1
+    pub fn define_cli_symbol(&self, spec: &str) -> Result<(), AssemblerError> {
+        let (name, value) = match spec.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (spec, None),
+        };
+        if name.is_empty() || name.contains('.') {
+            return Err(AssemblerError::BadSymbolDef(spec.to_string()));
+        }
+
+        let name = name.to_string();
+        let sym = match value {
+            None => Symbol::new_equ(name, 1),
+            Some(value) => match lexer::parse_number(value, self) {
+                Some(number) => Symbol::new_equ(name, number),
+                None => Symbol::new_equs(name, value.to_string()),
+            },
+        };
+        self.add_symbol(sym.clone())?;
+        self.predefined.borrow_mut().push(sym);
+        Ok(())
+    }
+
+    pub fn enable_warning(&mut self, class: WarningClass) {
+        self.enabled_warnings.insert(class);
+    }
+
+    pub fn disable_warning(&mut self, class: WarningClass) {
+        self.enabled_warnings.remove(&class);
+    }
+
+    pub fn set_warnings_as_errors(&mut self, enabled: bool) {
+        self.warnings_as_errors = enabled;
+    }
+
+    // Promote a single class of warnings to errors, even if it's disabled
+    pub fn set_warning_as_error(&mut self, class: WarningClass, enabled: bool) {
+        if enabled {
+            self.error_warnings.insert(class);
+        } else {
+            self.error_warnings.remove(&class);
         }
     }
 
+    // Writes each source line along with the address and bytes it produced
+    pub fn with_listing(mut self, out: impl Write + 'a) -> Self {
+        self.listing = Some(RefCell::new(Listing::new(out)));
+        self
+    }
+
+    // Where `PRINT` and `PRINTLN` write, instead of the standard output
+    pub fn with_print_output(mut self, out: impl Write + 'a) -> Self {
+        self.print_out = RefCell::new(Box::new(out));
+        self
+    }
+
     // === Main call ===
 
-    pub fn assemble(&mut self, mut f: impl Read) -> Result<(), io::Error> {
-        // Init all
+    pub fn assemble(&self, f: impl Read) -> Result<(), io::Error> {
+        self.reset();
+        self.assemble_append(f)
+    }
+
+    // Like `assemble`, but keeps the symbols, sections, etc. from previous calls, so that
+    // several files can be assembled together
+    pub fn assemble_append(&self, f: impl Read) -> Result<(), io::Error> {
+        let io_error = RefCell::new(None);
+        self.assemble_chars(CharReader::new(f, &io_error));
+        if let Some(err) = io_error.into_inner() {
+            return Err(err);
+        }
+        self.write_listing()
+    }
+
+    // Same as `assemble`, but there is nothing to read; only writing the listing can fail
+    pub fn assemble_str(&self, src: &str) -> Result<(), io::Error> {
+        self.reset();
+        self.assemble_chars(src.chars());
+        self.write_listing()
+    }
+
+    fn reset(&self) {
+        self.error_count.set(0);
+        self.halted.set(false);
+        self.aborted.set(false);
         self.symbols.borrow_mut().clear();
+        self.folded_names.borrow_mut().clear();
 
         self.add_symbol(Symbol::new_equ("_RS".to_string(), 0))
             .unwrap();
+        for sym in self.predefined.borrow().iter() {
+            if let Err(err) = self.add_symbol(sym.clone()) {
+                self.report_error(err);
+            }
+        }
+        self.sym_scope.replace(None);
+        self.forward_refs.borrow_mut().clear();
+        self.sections.borrow_mut().clear();
+        self.cur_section.set(None);
+        self.load_section.set(None);
+        self.section_stack.borrow_mut().clear();
+        self.macro_frames.borrow_mut().clear();
+        self.unique_ids.set(0);
+        self.expansion_ids.borrow_mut().clear();
+        self.charmaps.borrow_mut().clear();
+        self.new_charmap("main".to_string(), None).unwrap();
+        self.charmap_stack.borrow_mut().clear();
+        self.deps.borrow_mut().clear();
+        self.assertions.borrow_mut().clear();
+        self.options.replace(self.base_options.clone());
+        self.option_stack.borrow_mut().clear();
+    }
 
-        // FIXME: reading the whole file as a string sucks, using an Iterator over chars would be much better
-        let mut s = String::new();
-        f.read_to_string(&mut s)?;
+    fn assemble_chars(&self, chars: impl Iterator<Item = char>) {
+        // The listing only covers what is being assembled now
+        self.cur_location.replace(None);
+        if let Some(listing) = &self.listing {
+            listing.borrow_mut().clear();
+        }
 
         let lexer_state = RefCell::new(Lexer::new_state());
-        let lexer = Lexer::new(s.chars(), &lexer_state, self.diagnose, &self);
+        let lexer = Lexer::new(chars, &lexer_state, self);
 
         if let Err(err) = AsmParser::new().parse(self, &lexer_state, lexer) {
-            (self.diagnose)(Diagnostic::Error(err.into()));
+            self.report_error(err);
         }
-        Ok(())
+        if lexer_state.borrow().in_conditional() && !self.is_halted() {
+            self.report_error(AssemblerError::UntermConditional);
+        }
+        self.check_unused_locals();
+        self.cur_location.replace(None);
     }
 
-    // === Error reporting ===
+    // Only lexes the source, e.g. to debug the grammar; errors are reported as usual
+    pub fn dump_tokens(&self, src: &str) -> Vec<(Location, TokType, Location)> {
+        let lexer_state = RefCell::new(Lexer::new_state());
+        Lexer::new(src.chars(), &lexer_state, self)
+            .filter_map(|tok| tok.map_err(|err| self.report_error(err)).ok())
+            .collect()
+    }
 
-    pub fn assert(
-        &self,
-        assert_type: AssertType,
-        expr: i32,
-        msg: Option<String>,
-    ) -> Result<(), AssemblerError> {
-        if expr == 0 {
-            return Ok(());
+    fn write_listing(&self) -> Result<(), io::Error> {
+        match &self.listing {
+            Some(listing) => listing.borrow_mut().write(&self.sections.borrow()),
+            None => Ok(()),
         }
-        match assert_type {
-            AssertType::Warn => unimplemented!(),
-            AssertType::Error => unimplemented!(),
-            AssertType::Fatal => Err(AssemblerError::AssertFailure(msg)),
+    }
+
+    pub(crate) fn begin_line(&self, loc: Location) {
+        self.cur_location.replace(Some(loc));
+        if let Some(listing) = &self.listing {
+            listing.borrow_mut().begin_line();
         }
     }
 
-    // === Symbol management ===
+    // Where the line being assembled starts, e.g. to give context from the diagnostic callback
+    pub fn current_location(&self) -> Option<Location> {
+        self.cur_location.borrow().clone()
+    }
 
-    pub fn get_symbol_scope(&self) -> Option<Rc<Symbol>> {
-        self.sym_scope
+    // 0 outside of the source, like for symbols defined with `-D`
+    fn current_line_no(&self) -> u32 {
+        self.cur_location
             .borrow()
             .as_ref()
-            .and_then(|weak| weak.upgrade())
+            .map_or(0, Location::line_no)
     }
 
-    pub fn set_symbol_scope(&self, scope: Weak<Symbol>) {
-        self.sym_scope.replace(Some(scope));
+    // === Listing ===
+
+    pub(crate) fn is_listing(&self) -> bool {
+        self.listing.is_some()
     }
 
-    pub fn expand_sym_name(&self, name: String) -> Result<String, AssemblerError> {
-        if !name.starts_with('.') {
-            Ok(name)
-        } else {
-            if let Some(scope) = self.get_symbol_scope() {
-                Ok(format!("{}{}", scope.get_name(), name))
-            } else {
-                Err(AssemblerError::LocalInMainScope(name))
-            }
+    pub(crate) fn list_source_line(&self, line: String) {
+        if let Some(listing) = &self.listing {
+            listing.borrow_mut().add_line(line);
         }
     }
 
-    pub fn find_symbol(&self, name: &String) -> Option<Ref<Symbol>> {
-        self.symbols.borrow().get(name).map(|sym| Ref::map(sym))
+    fn list_bytes(&self, offset: usize, len: usize) {
+        if let Some(listing) = &self.listing {
+            let sect_id = self.cur_section.get().unwrap();
+            listing.borrow_mut().add_bytes(sect_id, offset, len);
+        }
     }
 
-    pub fn add_symbol(&self, sym: Symbol) -> Result<(), AssemblerError> {
-        if let Some(other) = self.symbols.borrow_mut().get_mut(sym.get_name()) {
-            other.redefine(sym)?;
-            Ok(())
-        } else {
-            self.symbols
-                .borrow_mut()
-                .insert(Rc::clone(sym.get_name()), sym);
-            Ok(())
+    // === Error reporting ===
+
+    pub fn error_count(&self) -> usize {
+        self.error_count.get()
+    }
+
+    fn emit_diagnostic(&self, diag: Diagnostic) {
+        if !self.aborted.get() && (self.diagnose)(diag).is_break() {
+            self.aborted.set(true);
+            self.halt();
         }
     }
 
-    pub(crate) fn advance_rs(&self, offset: i32) -> i32 {
-        let rs = self
-            .symbols
-            .borrow_mut()
-            .get_mut(&"_RS".to_string())
-            .unwrap();
-        let val = rs.get_value().unwrap();
-        rs.set_value(val + offset);
+    pub(crate) fn report_error(&self, err: impl Into<Error>) {
+        self.error_count.set(self.error_count.get() + 1);
+        self.emit_diagnostic(Diagnostic::Error(err.into()));
 
-        val
+        if self.error_count.get() == self.max_errors {
+            let err = AssemblerError::TooManyErrors(self.max_errors);
+            self.emit_diagnostic(Diagnostic::Error(err.into()));
+            self.aborted.set(true);
+            self.halt();
+        }
+    }
+
+    pub(crate) fn report_warning(&self, warning: Warning) {
+        let class = warning.class();
+        if self.error_warnings.contains(&class)
+            || (self.warnings_as_errors && self.enabled_warnings.contains(&class))
+        {
+            self.report_error(AssemblerError::PromotedWarning(warning));
+        } else if self.enabled_warnings.contains(&class) {
+            self.emit_diagnostic(Diagnostic::Warning(warning));
+        }
+    }
+
+    pub(crate) fn truncate_8bit(&self, val: i32) -> u8 {
+        if !(-128..=255).contains(&val) {
+            self.report_warning(Warning::Truncated8(val));
+        }
+        val as u8
+    }
+
+    // Stops the assembly right after the current line
+    pub(crate) fn halt(&self) {
+        self.halted.set(true);
+    }
+
+    pub(crate) fn is_halted(&self) -> bool {
+        self.halted.get()
+    }
+
+    // `FAIL` stops the assembly; the error is returned so it can be reported with its location
+    pub fn user_fail(&self, msg: String) -> Result<(), AssemblerError> {
+        self.halt();
+        Err(AssemblerError::UserFail(msg))
+    }
+
+    pub fn user_warn(&self, msg: String) {
+        self.report_warning(Warning::UserWarn(msg));
+    }
+
+    pub fn print(&self, args: Vec<PrintArg>, newline: bool) -> Result<(), AssemblerError> {
+        let mut text = String::new();
+        for arg in args {
+            match arg {
+                PrintArg::Str(string) => text.push_str(&string),
+                PrintArg::Num(expr, PrintFmt::Hex) => {
+                    text.push('$');
+                    text.push_str(&expr.to_hex(0)?);
+                }
+                PrintArg::Num(expr, PrintFmt::Unsigned) => {
+                    text.push_str(&(i32::try_from(expr)? as u32).to_string())
+                }
+                PrintArg::Num(expr, PrintFmt::Signed) => {
+                    text.push_str(&i32::try_from(expr)?.to_string())
+                }
+                PrintArg::Num(expr, PrintFmt::Fixed) => {
+                    text.push_str(&expr.to_fixed(5, self.options().fixed_precision)?)
+                }
+            }
+        }
+        if newline {
+            text.push('\n');
+        }
+
+        let mut out = self.print_out.borrow_mut();
+        out.write_all(text.as_bytes())
+            .and_then(|_| out.flush())
+            .map_err(AssemblerError::PrintWrite)
+    }
+
+    // The `PRINTx` directives predate `PRINT`, and print exactly one argument
+    pub fn print_obsolete(&self, directive: &str, arg: PrintArg) -> Result<(), AssemblerError> {
+        self.report_warning(Warning::ObsoleteSyntax(format!(
+            "`{}` is deprecated; use `PRINTLN` instead",
+            directive
+        )));
+        self.print(vec![arg], false)
+    }
+
+    pub fn assert(
+        &self,
+        assert_type: AssertType,
+        expr: Expression,
+        msg: Option<String>,
+    ) -> Result<(), AssemblerError> {
+        let val = match expr.plain() {
+            Expression::Known(val) => val,
+            expr => {
+                let pc = self.cur_section.get().map(|id| {
+                    let offset = self.sections.borrow()[id].get_size();
+                    (id, offset as u16)
+                });
+                self.assertions.borrow_mut().push(DeferredAssertion {
+                    assert_type,
+                    expr,
+                    msg,
+                    pc,
+                    location: self.current_location(),
+                });
+                return Ok(());
+            }
+        };
+
+        if val != 0 {
+            return Ok(());
+        }
+        match assert_type {
+            AssertType::Warn => {
+                self.report_warning(Warning::AssertFailure(msg));
+                Ok(())
+            }
+            AssertType::Error => Err(AssemblerError::AssertFailure(msg)),
+            AssertType::Fatal => {
+                self.halt();
+                Err(AssemblerError::AssertFailure(msg))
+            }
+        }
+    }
+
+    // Unlike `assert`, the condition must be known at assembly time
+    pub fn static_assert(
+        &self,
+        assert_type: AssertType,
+        expr: Expression,
+        msg: Option<String>,
+    ) -> Result<(), AssemblerError> {
+        let val = i32::try_from(expr)?;
+        self.assert(assert_type, val.into(), msg)
+    }
+
+    // === Symbol management ===
+
+    pub fn get_symbol_scope(&self) -> Option<Rc<String>> {
+        self.sym_scope.borrow().clone()
+    }
+
+    pub fn set_symbol_scope(&self, scope: Rc<String>) {
+        self.sym_scope.replace(Some(scope));
+    }
+
+    pub fn expand_sym_name(&self, name: String) -> Result<String, AssemblerError> {
+        if !name.starts_with('.') {
+            Ok(name)
+        } else {
+            if let Some(scope) = self.get_symbol_scope() {
+                // Like with `\@`, each macro invocation and loop iteration has its own locals
+                match self.expansion_ids.borrow().last() {
+                    Some(id) => Ok(format!("{}{}_u{}", scope, name, id)),
+                    None => Ok(format!("{}{}", scope, name)),
+                }
+            } else {
+                Err(AssemblerError::LocalInMainScope(name))
+            }
+        }
+    }
+
+    pub fn find_symbol(&self, name: &String) -> Option<Ref<'_, Symbol>> {
+        Ref::filter_map(self.symbols.borrow(), |symbols| symbols.get(name)).ok()
+    }
+
+    // Looks a symbol up as written in the source, i.e. local names are relative to the scope
+    pub fn resolve_symbol(&self, name: &str) -> Result<Option<Ref<'_, Symbol>>, AssemblerError> {
+        let name = self.expand_sym_name(name.to_string())?;
+        Ok(self.find_symbol(&name))
+    }
+
+    // Visits all symbols sorted by name; `f` must not define symbols, as the table is borrowed
+    pub fn for_each_symbol(&self, mut f: impl FnMut(&str, &Symbol)) {
+        for (name, sym) in self.symbols.borrow().iter() {
+            f(name, sym);
+        }
+    }
+
+    pub fn add_symbol(&self, mut sym: Symbol) -> Result<(), AssemblerError> {
+        sym.set_line_no(self.current_line_no());
+        let mut symbols = self.symbols.borrow_mut();
+        if let Some(other) = symbols.get_mut(sym.get_name()) {
+            other.redefine(sym)?;
+            Ok(())
+        } else {
+            let forward_ref = self.forward_refs.borrow_mut().remove(sym.get_name());
+            if forward_ref {
+                sym.mark_referenced();
+            }
+            // EQUS are only expanded as text, so earlier expressions can never be computed
+            let is_str = sym.get_str().is_some();
+            let name = Rc::clone(sym.get_name());
+            symbols.insert(Rc::clone(&name), sym);
+            drop(symbols);
+            self.index_symbol_name(Rc::clone(&name));
+            if forward_ref && is_str {
+                return Err(AssemblerError::StringSymbolForwardRef(name.to_string()));
+            }
+            Ok(())
+        }
+    }
+
+    // Lookups stay case-sensitive; this is only used to warn about likely typos
+    fn index_symbol_name(&self, name: Rc<String>) {
+        let mut folded_names = self.folded_names.borrow_mut();
+        let variants = folded_names.entry(name.to_lowercase()).or_default();
+        let existing = variants.first().map(|existing| existing.to_string());
+        variants.push(Rc::clone(&name));
+        drop(folded_names);
+
+        if let Some(existing) = existing {
+            self.report_warning(Warning::CaseCollision {
+                existing,
+                new: name.to_string(),
+            });
+        }
+    }
+
+    // `EQU` values must be known at assembly time, so that they can be used anywhere
+    pub fn def_equ(&self, name: String, value: Expression) -> Result<(), AssemblerError> {
+        let name = self.expand_sym_name(name)?;
+        self.add_symbol(Symbol::new_equ(name, i32::try_from(value)?))
+    }
+
+    pub fn def_set(&self, name: String, value: Expression) -> Result<(), AssemblerError> {
+        let name = self.expand_sym_name(name)?;
+        self.add_symbol(Symbol::new_set(name, i32::try_from(value)?))
+    }
+
+    // `FOR`'s arguments are `[start, ]stop[, step]`; returns its variable and iteration count
+    pub(crate) fn begin_for(
+        &self,
+        name: String,
+        start: Expression,
+        stop: Expression,
+        step: Expression,
+    ) -> Result<(LoopVar, usize), AssemblerError> {
+        let name = self.expand_sym_name(name)?;
+        let (start, stop, step) = (
+            i32::try_from(start)?,
+            i32::try_from(stop)?,
+            i32::try_from(step)?,
+        );
+        let (start, stop, step) = (i64::from(start), i64::from(stop), i64::from(step));
+        let count = match step {
+            0 => return Err(AssemblerError::ForStepZero),
+            1.. if start < stop => (stop - start - 1) / step + 1,
+            ..=-1 if start > stop => (start - stop - 1) / -step + 1,
+            _ => 0,
+        };
+
+        let var = LoopVar {
+            name,
+            value: start as i32,
+            step: step as i32,
+        };
+        self.set_loop_var(&var.name, var.value)?;
+        Ok((var, count as usize))
+    }
+
+    pub(crate) fn set_loop_var(&self, name: &str, value: i32) -> Result<(), AssemblerError> {
+        self.add_symbol(Symbol::new_set(name.to_string(), value))
+    }
+
+    // Like `add_symbol`, but existing `EQU` and `EQUS` symbols may be redefined
+    pub fn redef_symbol(&self, mut sym: Symbol) -> Result<(), AssemblerError> {
+        sym.set_line_no(self.current_line_no());
+        if let Some(other) = self.symbols.borrow_mut().get_mut(sym.get_name()) {
+            return other.force_redefine(sym);
+        }
+        self.add_symbol(sym)
+    }
+
+    // Symbols the object file refers to must stay defined
+    pub fn purge_symbol(&self, name: String) -> Result<(), AssemblerError> {
+        let name = self.expand_sym_name(name)?;
+        if name == "_RS" || name == "_NARG" {
+            return Err(AssemblerError::PurgeBuiltin(name));
+        }
+        match self.find_symbol(&name) {
+            Some(sym) if sym.is_exported() => return Err(AssemblerError::PurgeReferenced(name)),
+            Some(_) => (),
+            None => return Err(AssemblerError::SymbolUndefined(name)),
+        }
+
+        let in_patch = self.sections.borrow().iter().any(|sect| {
+            sect.get_patches()
+                .iter()
+                .any(|patch| patch.expr.references(&name))
+        });
+        let in_assertion = self
+            .assertions
+            .borrow()
+            .iter()
+            .any(|assertion| assertion.expr.references(&name));
+        if in_patch || in_assertion {
+            return Err(AssemblerError::PurgeReferenced(name));
+        }
+
+        self.symbols.borrow_mut().remove(&name);
+        if let Some(variants) = self.folded_names.borrow_mut().get_mut(&name.to_lowercase()) {
+            variants.retain(|variant| **variant != name);
+        }
+        Ok(())
+    }
+
+    // Warns about the current scope's local symbols that were never referenced
+    fn check_unused_locals(&self) {
+        let scope = match self.get_symbol_scope() {
+            Some(scope) => format!("{}.", scope),
+            None => return,
+        };
+        let unused: Vec<_> = self
+            .symbols
+            .borrow()
+            .values()
+            .filter(|sym| {
+                sym.get_name().starts_with(&scope) && !sym.is_referenced() && !sym.is_exported()
+            })
+            .map(|sym| sym.get_name().to_string())
+            .collect();
+
+        for name in unused {
+            self.report_warning(Warning::UnusedSymbol(name));
+        }
+    }
+
+    // Labels are relative to their section, so their value is usually left to the linker
+    pub fn def_label(&self, name: String, exported: bool) -> Result<(), AssemblerError> {
+        if !lexer::is_symbol_name(&name) {
+            return Err(AssemblerError::IllegalSymbolName(name));
+        }
+        let is_local = name.contains('.');
+        let name = self.expand_sym_name(name)?;
+        let section = match self.load_section.get() {
+            Some(id) => id,
+            None => self.cur_section_id()?,
+        };
+        let offset = self.sections.borrow()[section].get_size() as u16;
+
+        let mut sym = Symbol::new_label(name, section, offset);
+        if exported {
+            sym.export();
+        }
+        // Only global labels open a new scope for local ones
+        if !is_local {
+            self.check_unused_locals();
+            self.set_symbol_scope(Rc::clone(sym.get_name()));
+        }
+        self.add_symbol(sym)
+    }
+
+    // Symbols whose value isn't known yet are left for the linker to resolve
+    pub fn symbol_expr(&self, name: String) -> Result<Expression, AssemblerError> {
+        if name == "@" {
+            return self.pc_expr();
+        }
+        let name = self.expand_sym_name(name)?;
+        let sym = match self.find_symbol(&name) {
+            Some(sym) => sym,
+            None => {
+                let name = Rc::new(name);
+                self.forward_refs.borrow_mut().insert(Rc::clone(&name));
+                return Ok(Expression::Symbol(name));
+            }
+        };
+
+        sym.mark_referenced();
+        Ok(match sym.resolve(&self.sections.borrow()) {
+            Some(val) => Expression::Known(val),
+            None if sym.get_str().is_some() => {
+                return Err(AssemblerError::StringSymbolInExpr(name))
+            }
+            None => Expression::Symbol(Rc::clone(sym.get_name())),
+        })
+    }
+
+    // Labels in the same section are a constant distance apart, even if the section floats
+    pub fn binary_expr(
+        &self,
+        op: BinOp,
+        lhs: Expression,
+        rhs: Expression,
+    ) -> Result<Expression, AssemblerError> {
+        if op == BinOp::Sub {
+            if let (Some((lhs_sect, lhs_ofs)), Some((rhs_sect, rhs_ofs))) =
+                (self.label_pos(&lhs), self.label_pos(&rhs))
+            {
+                if lhs_sect == rhs_sect {
+                    return Ok(Expression::Known(i32::from(lhs_ofs) - i32::from(rhs_ofs)));
+                }
+            }
+        }
+        Expression::binary(op, lhs, rhs)
+    }
+
+    pub fn rs_value(&self) -> i32 {
+        self.find_symbol(&"_RS".to_string())
+            .and_then(|rs| rs.get_value())
+            .unwrap()
+    }
+
+    pub fn set_rs(&self, value: Expression) -> Result<(), AssemblerError> {
+        let value = i32::try_from(value)?;
+        let mut symbols = self.symbols.borrow_mut();
+        symbols
+            .get_mut(&"_RS".to_string())
+            .unwrap()
+            .set_value(value);
+        Ok(())
+    }
+
+    // Reserves `count` entries of `size` bytes, and returns the value `_RS` had before
+    pub(crate) fn advance_rs(&self, count: i32, size: i32) -> i32 {
+        let mut symbols = self.symbols.borrow_mut();
+        let rs = symbols.get_mut(&"_RS".to_string()).unwrap();
+        let val = rs.get_value().unwrap();
+
+        match count.checked_mul(size).and_then(|ofs| val.checked_add(ofs)) {
+            Some(new_val) => rs.set_value(new_val),
+            None => {
+                let new_val = val.saturating_add(count.saturating_mul(size));
+                rs.set_value(new_val);
+                drop(symbols);
+                self.report_warning(Warning::RsOverflow(new_val));
+            }
+        }
+        val
+    }
+
+    // === Macro management ===
+
+    pub fn define_macro(&self, name: String, body: String) -> Result<(), AssemblerError> {
+        self.add_symbol(Symbol::new_macro(name, body))
+    }
+
+    // Enters a macro, and returns its body for the lexer to expand
+    pub(crate) fn begin_macro(
+        &self,
+        name: String,
+        args: Vec<String>,
+    ) -> Result<Rc<String>, AssemblerError> {
+        let body = match self.find_symbol(&name) {
+            Some(sym) => sym.get_macro().cloned(),
+            // Misspelled instructions are parsed as macro invocations
+            None => {
+                let suggestion = lexer::suggest_mnemonic(&name);
+                return Err(AssemblerError::NotAMacro(name, suggestion));
+            }
+        };
+        let body = body.ok_or(AssemblerError::NotAMacro(name, None))?;
+        if self.macro_frames.borrow().len() >= self.max_recursion_depth {
+            return Err(AssemblerError::Recursion(self.max_recursion_depth));
+        }
+
+        let saved_narg = self.symbols.borrow_mut().remove(&"_NARG".to_string());
+        self.macro_frames.borrow_mut().push(MacroFrame {
+            args,
+            shift: 0,
+            saved_narg,
+        });
+        self.update_narg();
+        Ok(body)
+    }
+
+    pub(crate) fn new_unique_id(&self) -> u32 {
+        self.unique_ids.set(self.unique_ids.get() + 1);
+        self.unique_ids.get()
+    }
+
+    pub(crate) fn enter_expansion(&self, unique_id: u32) {
+        self.expansion_ids.borrow_mut().push(unique_id);
+    }
+
+    pub(crate) fn leave_expansion(&self) {
+        self.expansion_ids.borrow_mut().pop();
+    }
+
+    // Leaves the innermost macro, once the lexer is done with its body
+    pub(crate) fn end_macro(&self) {
+        let frame = self.macro_frames.borrow_mut().pop().unwrap();
+        let mut symbols = self.symbols.borrow_mut();
+        match frame.saved_narg {
+            Some(sym) => symbols.insert(Rc::clone(sym.get_name()), sym),
+            None => symbols.remove(&"_NARG".to_string()),
+        };
+    }
+
+    // `index` starts at 1, like `\1`
+    pub(crate) fn macro_arg(&self, index: usize) -> Result<String, AssemblerError> {
+        let frames = self.macro_frames.borrow();
+        let frame = frames.last().ok_or(AssemblerError::MacroArgOutsideMacro)?;
+        frame
+            .args
+            .get(frame.shift + index - 1)
+            .cloned()
+            .ok_or(AssemblerError::NoSuchMacroArg(index))
+    }
+
+    // Negative amounts shift arguments back in, as long as they don't go past the first one
+    pub fn shift_macro_args(&self, amount: i32) -> Result<(), AssemblerError> {
+        let mut frames = self.macro_frames.borrow_mut();
+        let frame = frames.last_mut().ok_or(AssemblerError::ShiftOutsideMacro)?;
+        let shift = frame.shift as i64 + i64::from(amount);
+        if shift < 0 || shift > frame.args.len() as i64 {
+            return Err(AssemblerError::ShiftPastEnd);
+        }
+        frame.shift = shift as usize;
+        drop(frames);
+        self.update_narg();
+        Ok(())
+    }
+
+    // `_NARG` always reflects the innermost macro's remaining arguments
+    fn update_narg(&self) {
+        let narg = match self.macro_frames.borrow().last() {
+            Some(frame) => frame.args.len() - frame.shift,
+            None => return,
+        };
+        let sym = Symbol::new_equ("_NARG".to_string(), narg as i32);
+        self.symbols
+            .borrow_mut()
+            .insert(Rc::clone(sym.get_name()), sym);
+    }
+
+    // === Section management ===
+
+    fn add_section(&self, name: String, attrs: section::Attrs) -> Result<usize, AssemblerError> {
+        let mut sections = self.sections.borrow_mut();
+        if sections.iter().any(|sect| sect.get_name() == name) {
+            return Err(AssemblerError::SectionRedef(name));
+        }
+
+        sections.push(Section::new(name, attrs));
+        Ok(sections.len() - 1)
+    }
+
+    pub fn new_section(&self, name: String, attrs: section::Attrs) -> Result<(), AssemblerError> {
+        let existing = self
+            .sections
+            .borrow()
+            .iter()
+            .position(|sect| sect.get_name() == name && sect.continues_with(&attrs));
+        let id = match existing {
+            Some(id) => id,
+            None => self.add_section(name, attrs)?,
+        };
+        self.cur_section.set(Some(id));
+        // Changing sections ends any `LOAD` block
+        self.load_section.set(None);
+        Ok(())
+    }
+
+    // Code is still emitted to the current section, but labels are defined in the new one
+    pub fn load_block(&self, name: String, attrs: section::Attrs) -> Result<(), AssemblerError> {
+        if self.load_section.get().is_some() {
+            return Err(AssemblerError::NestedLoad);
+        }
+        self.data_section()?;
+
+        let id = self.add_section(name, attrs)?;
+        self.load_section.set(Some(id));
+        Ok(())
+    }
+
+    pub fn end_load(&self) -> Result<(), AssemblerError> {
+        match self.load_section.take() {
+            Some(_) => Ok(()),
+            None => Err(AssemblerError::EndlWithoutLoad),
+        }
+    }
+
+    // Like RGBDS, no section is active after pushing, until the next `SECTION`
+    pub fn push_section(&self) {
+        self.section_stack.borrow_mut().push(SectionFrame {
+            section: self.cur_section.take(),
+            load_section: self.load_section.take(),
+            scope: self.sym_scope.take(),
+        });
+    }
+
+    // Sections only ever grow, so restoring them restores their PC (and thus `@`) too
+    pub fn pop_section(&self) -> Result<(), AssemblerError> {
+        let frame = self
+            .section_stack
+            .borrow_mut()
+            .pop()
+            .ok_or(AssemblerError::PopEmptySectionStack)?;
+        self.cur_section.set(frame.section);
+        self.load_section.set(frame.load_section);
+        self.sym_scope.replace(frame.scope);
+        Ok(())
+    }
+
+    fn advance_load(&self, len: usize) {
+        if let Some(id) = self.load_section.get() {
+            self.sections.borrow_mut()[id].advance(len);
+        }
+    }
+
+    // Checks the placement of all sections, reporting every problem at once
+    pub fn validate_layout(&self) -> Vec<AssemblerError> {
+        let sections = self.sections.borrow();
+        let mut errors: Vec<_> = sections
+            .iter()
+            .filter(|sect| !sect.fits_in_region())
+            .map(|sect| AssemblerError::SectionOverflow(sect.get_name().to_string()))
+            .collect();
+
+        // Only sections whose address and bank are both known can be checked for overlap
+        let mut fixed: Vec<_> = sections
+            .iter()
+            .filter(|sect| sect.get_size() != 0 && sect.get_bank().is_some())
+            .filter_map(|sect| Some((sect.get_org()?, sect)))
+            .collect();
+        fixed.sort_by_key(|(org, _)| *org);
+        for (i, (org, sect)) in fixed.iter().enumerate() {
+            let end = usize::from(*org) + sect.get_size();
+            for (other_org, other) in &fixed[i + 1..] {
+                if usize::from(*other_org) >= end {
+                    break;
+                }
+                if other.get_type() == sect.get_type() && other.get_bank() == sect.get_bank() {
+                    errors.push(AssemblerError::SectionOverlap(
+                        sect.get_name().to_string(),
+                        other.get_name().to_string(),
+                    ));
+                }
+            }
+        }
+        errors
+    }
+
+    // The layout of all sections so far, in declaration order
+    pub fn sections_iter(&self) -> impl Iterator<Item = SectionInfo> {
+        let infos: Vec<_> = self.sections.borrow().iter().map(Section::info).collect();
+        infos.into_iter()
+    }
+
+    // What has been emitted to a section so far, bypassing the object file
+    pub fn section_bytes(&self, name: &str) -> Option<Ref<'_, [u8]>> {
+        Ref::filter_map(self.sections.borrow(), |sections| {
+            let sect = sections.iter().find(|sect| sect.get_name() == name)?;
+            Some(sect.get_data())
+        })
+        .ok()
+    }
+
+    // A section's size is only final once it can't grow anymore, so it's often left to the linker
+    pub fn section_size_expr(&self, name: String) -> Expression {
+        let sections = self.sections.borrow();
+        let is_open =
+            |id| Some(id) == self.cur_section.get() || Some(id) == self.load_section.get();
+
+        match sections.iter().position(|sect| sect.get_name() == name) {
+            Some(id) if !is_open(id) && !sections[id].is_fragment() => {
+                Expression::Known(sections[id].get_size() as i32)
+            }
+            _ => Expression::SectSize(Rc::new(name)),
+        }
+    }
+
+    pub fn section_start_expr(&self, name: String) -> Expression {
+        let sections = self.sections.borrow();
+        let sect = sections.iter().find(|sect| sect.get_name() == name);
+        match sect.and_then(Section::get_org) {
+            Some(org) => Expression::Known(org.into()),
+            None => Expression::SectStart(Rc::new(name)),
+        }
+    }
+
+    // The address of the current position, only known if the section has a fixed address
+    pub fn pc_expr(&self) -> Result<Expression, AssemblerError> {
+        let id = self
+            .load_section
+            .get()
+            .or_else(|| self.cur_section.get())
+            .ok_or(AssemblerError::PcOutsideSection)?;
+        let sections = self.sections.borrow();
+        let sect = &sections[id];
+        let offset = sect.get_size() as i32;
+
+        Ok(match sect.get_org() {
+            Some(org) => Expression::Known(i32::from(org) + offset),
+            None => Expression::binary(
+                BinOp::Add,
+                Expression::SectStart(Rc::new(sect.get_name().to_string())),
+                Expression::Known(offset),
+            )?,
+        })
+    }
+
+    // The section and offset of a label, if that's what the expression is
+    fn label_pos(&self, expr: &Expression) -> Option<(usize, u16)> {
+        match expr {
+            Expression::Symbol(name) => self.find_symbol(name)?.get_label(),
+            _ => None,
+        }
+    }
+
+    // The section and offset of the current position, like a label defined here would have
+    fn pc_pos(&self) -> Option<(usize, u16)> {
+        let id = self.load_section.get().or_else(|| self.cur_section.get())?;
+        let offset = self.sections.borrow()[id].get_size();
+        u16::try_from(offset).ok().map(|offset| (id, offset))
+    }
+
+    // Quick snippets may not bother with `SECTION`, so the first code or label opens a ROM0 one
+    fn cur_section_id(&self) -> Result<usize, AssemblerError> {
+        if let Some(id) = self.cur_section.get() {
+            return Ok(id);
+        }
+        if !self.sections.borrow().is_empty() {
+            return Err(AssemblerError::NoSection);
+        }
+
+        self.report_warning(Warning::CodeBeforeSection);
+        let attrs = section::Attrs::new(section::Type::Rom0, None, false)?;
+        let id = self.add_section(section::IMPLICIT_NAME.to_string(), attrs)?;
+        self.cur_section.set(Some(id));
+        Ok(id)
+    }
+
+    fn data_section(&self) -> Result<RefMut<'_, Section>, AssemblerError> {
+        let id = self.cur_section_id()?;
+        let sect = RefMut::map(self.sections.borrow_mut(), |sections| &mut sections[id]);
+
+        if !sect.can_contain_data() {
+            return Err(AssemblerError::DataInRamSection(
+                sect.get_name().to_string(),
+            ));
+        }
+        Ok(sect)
+    }
+
+    pub fn emit_bytes(&self, bytes: &[u8]) -> Result<(), AssemblerError> {
+        let offset = {
+            let mut sect = self.data_section()?;
+            let offset = sect.get_size();
+            sect.emit_bytes(bytes);
+            offset
+        };
+        self.advance_load(bytes.len());
+        self.list_bytes(offset, bytes.len());
+        Ok(())
+    }
+
+    fn emit_expr(&self, expr: Expression, patch_type: PatchType) -> Result<(), AssemblerError> {
+        match (expr.plain(), patch_type) {
+            (Expression::Known(val), PatchType::Byte) => {
+                self.emit_bytes(&[self.truncate_8bit(val)])
+            }
+            (Expression::Known(val), PatchType::Word) => {
+                self.emit_bytes(&(val as u16).to_le_bytes())
+            }
+            (Expression::Known(val), PatchType::Long) => self.emit_bytes(&val.to_le_bytes()),
+            // `jr` offsets depend on the instruction's own address, so they're always patched
+            (expr, patch_type) => {
+                let (offset, len) = {
+                    let mut sect = self.data_section()?;
+                    let offset = sect.get_size();
+                    sect.emit_patch(patch_type, expr, self.current_line_no());
+                    (offset, sect.get_size() - offset)
+                };
+                self.advance_load(len);
+                self.list_bytes(offset, len);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn emit_byte(&self, expr: Expression) -> Result<(), AssemblerError> {
+        self.emit_expr(expr, PatchType::Byte)
+    }
+
+    // Like `emit_byte`, but gfx constants are emitted whole, like RGBDS' `DB` does
+    pub fn emit_db(&self, expr: Expression) -> Result<(), AssemblerError> {
+        match expr {
+            Expression::Gfx(val) => self.emit_bytes(&(val as u16).to_le_bytes()),
+            expr => self.emit_byte(expr),
+        }
+    }
+
+    pub fn emit_word(&self, expr: Expression) -> Result<(), AssemblerError> {
+        self.emit_expr(expr, PatchType::Word)
+    }
+
+    pub fn emit_long(&self, expr: Expression) -> Result<(), AssemblerError> {
+        self.emit_expr(expr, PatchType::Long)
+    }
+
+    pub fn emit_jr(&self, target: Expression) -> Result<(), AssemblerError> {
+        self.emit_expr(target, PatchType::Jr)
+    }
+
+    // Checks a `jr` about to be emitted, if its offset is already known, e.g. within a section
+    // The linker checks the others
+    pub fn check_jr_range(&self, target: &Expression) -> Result<(), AssemblerError> {
+        // `jr` offsets are relative to the end of the 2-byte instruction
+        let ofs = match (self.label_pos(target), self.pc_pos()) {
+            (Some((sect, ofs)), Some((pc_sect, pc_ofs))) if sect == pc_sect => {
+                i32::from(ofs) - i32::from(pc_ofs) - 2
+            }
+            _ => match self
+                .pc_expr()
+                .and_then(|pc| self.binary_expr(BinOp::Sub, target.clone(), pc))
+            {
+                Ok(Expression::Known(dist)) => dist - 2,
+                _ => return Ok(()),
+            },
+        };
+        match Expression::Known(ofs).as_i8() {
+            Err(AssemblerError::ValueOutOfRange8(ofs)) => Err(AssemblerError::JrOffsetRange(ofs)),
+            res => res.map(|_| ()),
+        }
+    }
+
+    // Suggests `jr` for a `jp` about to be emitted, if both its address and target are known
+    pub fn check_jp_range(&self, target: &Expression) {
+        let pc = match self.pc_expr() {
+            Ok(pc) => pc,
+            Err(_) => return,
+        };
+        // `jr` offsets are relative to the end of the 2-byte instruction
+        if let Ok(Expression::Known(dist)) = self.binary_expr(BinOp::Sub, target.clone(), pc) {
+            if (-128..=127).contains(&(dist - 2)) {
+                self.report_warning(Warning::CouldBeJr { distance: dist - 2 });
+            }
+        }
+    }
+
+    pub fn emit_string(&self, string: &str) -> Result<(), AssemblerError> {
+        let bytes = self.charmaps.borrow()[&*self.cur_charmap.borrow()].translate(string);
+        self.emit_bytes(&bytes)
+    }
+
+    pub fn emit_fill(&self, byte: u8, count: usize) -> Result<(), AssemblerError> {
+        self.emit_bytes(&vec![byte; count])
+    }
+
+    // RAM sections have no data, so they are only grown
+    pub fn emit_ds(&self, len: usize) -> Result<(), AssemblerError> {
+        let id = self.cur_section_id()?;
+        if !self.sections.borrow()[id].can_contain_data() {
+            self.sections.borrow_mut()[id].advance(len);
+            self.advance_load(len);
+            return Ok(());
+        }
+
+        let fill_byte = self.options.borrow().fill_byte;
+        self.emit_fill(fill_byte, len)
+    }
+
+    // Paths are looked up relative to the working directory, then to each include directory
+    pub(crate) fn find_file(&self, path: &str) -> PathBuf {
+        if Path::new(path).exists() {
+            return path.into();
+        }
+        self.include_dirs
+            .iter()
+            .map(|dir| dir.join(path))
+            .find(|path| path.exists())
+            .unwrap_or_else(|| path.into())
+    }
+
+    // Reads a file for `INCLUDE` or `INCBIN`, which the output then depends on
+    pub(crate) fn read_file(&self, path: &str) -> Result<Vec<u8>, AssemblerError> {
+        if let Some(data) = self
+            .include_resolver
+            .as_ref()
+            .and_then(|resolve| resolve(path))
+        {
+            self.add_dependency(path.to_string());
+            return Ok(data);
+        }
+
+        let path = self.find_file(path).display().to_string();
+        self.add_dependency(path.clone());
+        std::fs::read(&path).map_err(|err| AssemblerError::FileRead(path, err))
+    }
+
+    // Without a length, everything from the offset to the end of the file is included
+    pub fn incbin(
+        &self,
+        path: String,
+        offset: usize,
+        len: Option<usize>,
+    ) -> Result<(), AssemblerError> {
+        let data = self.read_file(&path)?;
+        let end = match len {
+            Some(len) => offset.saturating_add(len),
+            None => data.len().max(offset),
+        };
+        if end > data.len() {
+            return Err(AssemblerError::IncbinOutOfBounds {
+                path,
+                file_len: data.len(),
+                requested: end,
+            });
+        }
+        self.emit_bytes(&data[offset..end])
+    }
+
+    // === Option management ===
+
+    pub(crate) fn options(&self) -> Ref<'_, AssemblerOptions> {
+        self.options.borrow()
+    }
+
+    pub fn opt_set(&self, spec: &str) -> Result<(), AssemblerError> {
+        self.options.borrow_mut().set(spec)
+    }
+
+    pub fn push_options(&self) {
+        self.option_stack
+            .borrow_mut()
+            .push(self.options.borrow().clone());
+    }
+
+    pub fn pop_options(&self) -> Result<(), AssemblerError> {
+        match self.option_stack.borrow_mut().pop() {
+            Some(options) => {
+                self.options.replace(options);
+                Ok(())
+            }
+            None => Err(AssemblerError::PopEmptyOptionStack),
+        }
+    }
+
+    // === Charmap management ===
+    // Like symbols, charmaps only last until the next `assemble`, which starts over from an empty
+    // "main" charmap
+
+    // The new charmap starts out as a copy of `base`, if any
+    pub fn new_charmap(&self, name: String, base: Option<&str>) -> Result<(), AssemblerError> {
+        let mut charmaps = self.charmaps.borrow_mut();
+        if charmaps.contains_key(&name) {
+            return Err(AssemblerError::CharmapRedef(name));
+        }
+        let charmap = match base {
+            Some(base) => charmaps
+                .get(base)
+                .cloned()
+                .ok_or_else(|| AssemblerError::NoSuchCharmap(base.to_string()))?,
+            None => Charmap::new(),
+        };
+
+        charmaps.insert(name.clone(), charmap);
+        self.cur_charmap.replace(name);
+        Ok(())
+    }
+
+    pub fn set_charmap(&self, name: String) -> Result<(), AssemblerError> {
+        if !self.charmaps.borrow().contains_key(&name) {
+            return Err(AssemblerError::NoSuchCharmap(name));
+        }
+
+        self.cur_charmap.replace(name);
+        Ok(())
+    }
+
+    pub fn charmap_push(&self) {
+        self.charmap_stack
+            .borrow_mut()
+            .push(self.cur_charmap.borrow().clone());
+    }
+
+    pub fn charmap_pop(&self) -> Result<(), AssemblerError> {
+        match self.charmap_stack.borrow_mut().pop() {
+            Some(name) => {
+                self.cur_charmap.replace(name);
+                Ok(())
+            }
+            None => Err(AssemblerError::PopEmptyCharmapStack),
+        }
+    }
+
+    pub fn char_value(&self, string: &str) -> Result<i32, AssemblerError> {
+        self.charmaps.borrow()[&*self.cur_charmap.borrow()]
+            .translate_char(string)
+            .ok_or_else(|| AssemblerError::MultiCharConstant(string.to_string()))
+    }
+
+    pub fn charmap_add(&self, from: &str, to: Vec<u8>) {
+        self.charmaps
+            .borrow_mut()
+            .get_mut(&*self.cur_charmap.borrow())
+            .unwrap()
+            .add(from, to);
+    }
+}
+
+// For the common case of turning some source into an object file, without any configuration
+pub fn assemble_to_object(src: &str, opts: &AssemblerOptions) -> Result<Vec<u8>, Vec<Diagnostic>> {
+    let diags = RefCell::new(Vec::new());
+    let diagnose = |diag: Diagnostic| {
+        diags.borrow_mut().push(diag);
+        ControlFlow::Continue(())
+    };
+    let mut asm = Assembler::new(&diagnose);
+    asm.set_options(opts.clone());
+
+    // Only writing the listing can fail, and there is none
+    asm.assemble_str(src).unwrap();
+    if asm.error_count() != 0 {
+        return Err(diags.take());
     }
+    let mut object = Vec::new();
+    asm.write_object(&mut object).unwrap();
+    Ok(object)
 }
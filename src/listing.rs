@@ -0,0 +1,115 @@
+use crate::section::Section;
+use std::io::{self, Write};
+
+const BYTES_PER_ROW: usize = 4;
+
+// Bytes emitted by a single source line
+#[derive(Debug)]
+struct Chunk {
+    line_no: u32,
+    sect_id: usize,
+    offset: usize,
+    len: usize,
+}
+
+pub struct Listing<'a> {
+    out: Box<dyn Write + 'a>,
+    lines: Vec<String>,
+    chunks: Vec<Chunk>,
+    // Line that bytes are being emitted for
+    cur_line: u32,
+}
+
+fn write_row(
+    out: &mut dyn Write,
+    addr: Option<usize>,
+    bytes: &[u8],
+    source: &str,
+) -> io::Result<()> {
+    let addr = addr.map_or(String::new(), |addr| format!("{:04x}", addr));
+    let bytes: Vec<_> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let row = format!(
+        "{:4}  {:width$}  {}",
+        addr,
+        bytes.join(" "),
+        source,
+        width = BYTES_PER_ROW * 3 - 1
+    );
+    writeln!(out, "{}", row.trim_end())
+}
+
+impl<'a> Listing<'a> {
+    pub fn new(out: impl Write + 'a) -> Self {
+        Self {
+            out: Box::new(out),
+            lines: Vec::new(),
+            chunks: Vec::new(),
+            cur_line: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.chunks.clear();
+        self.cur_line = 0;
+    }
+
+    pub fn add_line(&mut self, line: String) {
+        self.lines.push(line);
+    }
+
+    // Source line numbers can't be used, as they restart within expansions
+    pub fn begin_line(&mut self) {
+        self.cur_line = self.lines.len() as u32 + 1;
+    }
+
+    pub fn add_bytes(&mut self, sect_id: usize, offset: usize, len: usize) {
+        // An empty chunk would still swallow its line
+        if len == 0 {
+            return;
+        }
+        let line_no = self.cur_line;
+        if let Some(last) = self.chunks.last_mut() {
+            if last.line_no == line_no
+                && last.sect_id == sect_id
+                && last.offset + last.len == offset
+            {
+                last.len += len;
+                return;
+            }
+        }
+        self.chunks.push(Chunk {
+            line_no,
+            sect_id,
+            offset,
+            len,
+        });
+    }
+
+    // Lines are written with their address and bytes, which may spill over onto more rows
+    pub fn write(&mut self, sections: &[Section]) -> io::Result<()> {
+        let mut chunks = self.chunks.iter().peekable();
+
+        for (line_no, line) in (1..).zip(&self.lines) {
+            let mut source = line.as_str();
+            let mut listed = false;
+
+            while let Some(chunk) = chunks.next_if(|chunk| chunk.line_no == line_no) {
+                let sect = &sections[chunk.sect_id];
+                let org = sect.get_org().map_or(0, usize::from);
+                let data = &sect.get_data()[chunk.offset..chunk.offset + chunk.len];
+
+                for (i, row) in data.chunks(BYTES_PER_ROW).enumerate() {
+                    let addr = org + chunk.offset + i * BYTES_PER_ROW;
+                    write_row(&mut self.out, Some(addr), row, source)?;
+                    source = "";
+                }
+                listed = true;
+            }
+            if !listed {
+                write_row(&mut self.out, None, &[], source)?;
+            }
+        }
+        self.out.flush()
+    }
+}
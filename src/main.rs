@@ -1,16 +1,120 @@
 extern crate rsgbasm;
 use rsgbasm::Assembler;
 use rsgbasm::Diagnostic;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::ops::ControlFlow;
+use std::process;
 
-fn main() {
-    let mut assembler = Assembler::new(&|diag| match diag {
-        Diagnostic::Warning(warn) => println!("{:?}", warn),
-        Diagnostic::Error(err) => println!("{}", err),
+#[derive(Default)]
+struct Args {
+    inputs: Vec<String>,
+    output: Option<String>,
+    include_dirs: Vec<String>,
+    defines: Vec<String>,
+    max_recursion_depth: Option<usize>,
+    max_errors: Option<usize>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args::default();
+
+    while let Some(arg) = args.next() {
+        let mut value = |name| {
+            args.next()
+                .ok_or_else(|| format!("Option {} requires an argument", name))
+        };
+
+        match arg.as_str() {
+            "-o" => parsed.output = Some(value("-o")?),
+            "-I" => parsed.include_dirs.push(value("-I")?),
+            "-D" => parsed.defines.push(value("-D")?),
+            "-r" => {
+                let depth = value("-r")?;
+                parsed.max_recursion_depth = Some(
+                    depth
+                        .parse()
+                        .map_err(|_| format!("Invalid recursion depth \"{}\"", depth))?,
+                );
+            }
+            "-X" => {
+                let count = value("-X")?;
+                parsed.max_errors = Some(
+                    count
+                        .parse()
+                        .map_err(|_| format!("Invalid error count \"{}\"", count))?,
+                );
+            }
+            _ if arg.starts_with('-') => return Err(format!("Unknown option \"{}\"", arg)),
+            _ => parsed.inputs.push(arg),
+        }
+    }
+    Ok(parsed)
+}
+
+fn run(args: impl Iterator<Item = String>) -> Result<(), String> {
+    let args = parse_args(args)?;
+
+    let mut assembler = Assembler::new(&|diag| {
+        match diag {
+            Diagnostic::Warning(warn) => println!("Warning: {}", warn),
+            Diagnostic::Error(err) => println!("{}", err),
+        }
+        ControlFlow::Continue(())
     });
-    // TODO: use std::env::args
+    for dir in args.include_dirs {
+        assembler.add_include_dir(dir);
+    }
+    if let Some(depth) = args.max_recursion_depth {
+        assembler.set_max_recursion_depth(depth);
+    }
+    if let Some(count) = args.max_errors {
+        assembler.set_max_errors(count);
+    }
+    for spec in &args.defines {
+        assembler
+            .define_cli_symbol(spec)
+            .map_err(|err| err.to_string())?;
+    }
 
-    match assembler.assemble(std::io::stdin()) {
-        Ok(()) => println!("Success!"),
-        Err(err) => println!("Error: {}", err),
+    // Each input file is lexed on its own, but they share symbols and sections
+    if args.inputs.is_empty() {
+        assembler
+            .assemble(io::stdin())
+            .map_err(|err| format!("Error: {}", err))?;
+    }
+    for (i, path) in args.inputs.iter().enumerate() {
+        let file =
+            File::open(path).map_err(|err| format!("Failed to open \"{}\": {}", path, err))?;
+        let result = if i == 0 {
+            assembler.assemble(file)
+        } else {
+            assembler.assemble_append(file)
+        };
+        result.map_err(|err| format!("Error reading \"{}\": {}", path, err))?;
+    }
+    let layout_errors = assembler.validate_layout();
+    for err in &layout_errors {
+        println!("{}", err);
+    }
+    let error_count = assembler.error_count() + layout_errors.len();
+    if error_count != 0 {
+        return Err(format!("Assembly aborted ({} errors)!", error_count));
+    }
+
+    if let Some(path) = args.output {
+        let file =
+            File::create(&path).map_err(|err| format!("Failed to create \"{}\": {}", path, err))?;
+        assembler
+            .write_object(BufWriter::new(file))
+            .map_err(|err| format!("Failed to write \"{}\": {}", path, err))?;
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(msg) = run(std::env::args().skip(1)) {
+        eprintln!("{}", msg);
+        process::exit(1);
     }
 }
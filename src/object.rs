@@ -0,0 +1,312 @@
+use crate::expression::{BinOp, Expression, PatchKind, RelocBase, UnOp};
+use crate::section::{self, PatchType};
+use crate::{Assembler, AssemblerError, AssertType, Location};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+// RGBDS object format, see rgbds(5)
+const MAGIC: &[u8] = b"RGB9";
+const REVISION: i32 = 9;
+// Not part of the format: set in the revision of objects written despite errors
+const REVISION_HAD_ERRORS: i32 = 0x100;
+
+const SYM_LOCAL: u8 = 0;
+const SYM_IMPORT: u8 = 1;
+const SYM_EXPORT: u8 = 2;
+
+const NODE_FILE: u8 = 1;
+
+const SECT_FRAGMENT: u8 = 0x40;
+
+const RPN_HRAM: u8 = 0x60;
+const RPN_CONST: u8 = 0x80;
+const RPN_SIZEOF_SECT: u8 = 0x53;
+const RPN_STARTOF_SECT: u8 = 0x54;
+const RPN_SYM: u8 = 0x81;
+
+// === Utilities ===
+
+fn write_byte(out: &mut impl Write, val: u8) -> io::Result<()> {
+    out.write_all(&[val])
+}
+
+fn write_long(out: &mut impl Write, val: i32) -> io::Result<()> {
+    out.write_all(&val.to_le_bytes())
+}
+
+fn write_string(out: &mut impl Write, string: &str) -> io::Result<()> {
+    out.write_all(string.as_bytes())?;
+    write_byte(out, 0)
+}
+
+fn sect_type_id(sect_type: &section::Type) -> u8 {
+    match sect_type {
+        section::Type::Wram0 => 0,
+        section::Type::Vram => 1,
+        section::Type::Romx => 2,
+        section::Type::Rom0 => 3,
+        section::Type::Hram => 4,
+        section::Type::Wramx => 5,
+        section::Type::Sram => 6,
+        section::Type::Oam => 7,
+    }
+}
+
+fn patch_type_id(patch_type: PatchType) -> u8 {
+    match patch_type {
+        PatchType::Byte => 0,
+        PatchType::Word => 1,
+        PatchType::Long => 2,
+        PatchType::Jr => 3,
+    }
+}
+
+fn assert_type_id(assert_type: &AssertType) -> u8 {
+    match assert_type {
+        AssertType::Warn => 0,
+        AssertType::Error => 1,
+        AssertType::Fatal => 2,
+    }
+}
+
+fn un_op_id(op: UnOp) -> u8 {
+    match op {
+        UnOp::Neg => 0x05,
+        UnOp::Cpl => 0x13,
+        UnOp::LogNot => 0x23,
+        UnOp::Hram => RPN_HRAM,
+    }
+}
+
+fn bin_op_id(op: BinOp) -> u8 {
+    match op {
+        BinOp::Add => 0x00,
+        BinOp::Sub => 0x01,
+        BinOp::Mul => 0x02,
+        BinOp::Div => 0x03,
+        BinOp::Mod => 0x04,
+        BinOp::Or => 0x10,
+        BinOp::And => 0x11,
+        BinOp::Xor => 0x12,
+        BinOp::LogAnd => 0x21,
+        BinOp::LogOr => 0x22,
+        BinOp::Eq => 0x30,
+        BinOp::Neq => 0x31,
+        BinOp::Gt => 0x32,
+        BinOp::Lt => 0x33,
+        BinOp::Gte => 0x34,
+        BinOp::Lte => 0x35,
+        BinOp::Shl => 0x40,
+        BinOp::Shr => 0x41,
+    }
+}
+
+fn collect_symbols(expr: &Expression, names: &mut Vec<Rc<String>>) {
+    match expr {
+        Expression::Known(_)
+        | Expression::Gfx(_)
+        | Expression::SectSize(_)
+        | Expression::SectStart(_) => (),
+        Expression::Symbol(name) => {
+            if !names.contains(name) {
+                names.push(Rc::clone(name));
+            }
+        }
+        Expression::Unary(_, expr) => collect_symbols(expr, names),
+        Expression::Binary(_, lhs, rhs) => {
+            collect_symbols(lhs, names);
+            collect_symbols(rhs, names);
+        }
+    }
+}
+
+fn write_rpn(expr: &Expression, sym_ids: &HashMap<Rc<String>, i32>, rpn: &mut Vec<u8>) {
+    match expr {
+        Expression::Known(val) | Expression::Gfx(val) => {
+            rpn.push(RPN_CONST);
+            rpn.extend_from_slice(&val.to_le_bytes());
+        }
+        Expression::Symbol(name) => {
+            rpn.push(RPN_SYM);
+            rpn.extend_from_slice(&sym_ids[name].to_le_bytes());
+        }
+        Expression::SectSize(name) => {
+            rpn.push(RPN_SIZEOF_SECT);
+            rpn.extend_from_slice(name.as_bytes());
+            rpn.push(0);
+        }
+        Expression::SectStart(name) => {
+            rpn.push(RPN_STARTOF_SECT);
+            rpn.extend_from_slice(name.as_bytes());
+            rpn.push(0);
+        }
+        Expression::Unary(op, expr) => {
+            write_rpn(expr, sym_ids, rpn);
+            rpn.push(un_op_id(*op));
+        }
+        Expression::Binary(op, lhs, rhs) => {
+            write_rpn(lhs, sym_ids, rpn);
+            write_rpn(rhs, sym_ids, rpn);
+            rpn.push(bin_op_id(*op));
+        }
+    }
+}
+
+// Relocatable expressions are written in their simplest form, i.e. `base + offset`
+fn write_patch_rpn(expr: &Expression, sym_ids: &HashMap<Rc<String>, i32>, rpn: &mut Vec<u8>) {
+    match expr.classify() {
+        PatchKind::Absolute(val) => write_rpn(&Expression::Known(val), sym_ids, rpn),
+        PatchKind::Relative(base, ofs) => {
+            let base = match base {
+                RelocBase::Symbol(name) => Expression::Symbol(name),
+                RelocBase::SectStart(name) => Expression::SectStart(name),
+            };
+            if ofs == 0 {
+                write_rpn(&base, sym_ids, rpn);
+            } else {
+                let ofs = Expression::Known(ofs);
+                write_rpn(
+                    &Expression::Binary(BinOp::Add, Box::new(base), Box::new(ofs)),
+                    sym_ids,
+                    rpn,
+                );
+            }
+        }
+        PatchKind::Opaque => write_rpn(expr, sym_ids, rpn),
+    }
+}
+
+impl Assembler<'_> {
+    pub fn write_object(&self, mut out: impl Write) -> io::Result<()> {
+        let had_errors = self.error_count() != 0;
+        if had_errors && !self.partial_output() {
+            let err = AssemblerError::HadErrors(self.error_count());
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, err.to_string()));
+        }
+
+        let symbols = self.symbols.borrow();
+        let sections = self.sections.borrow();
+        let assertions = self.assertions.borrow();
+
+        // Labels and exported symbols are always written out, other symbols only if referenced
+        let mut sym_names: Vec<_> = symbols
+            .values()
+            .filter(|sym| sym.get_label().is_some() || sym.is_exported())
+            .map(|sym| Rc::clone(sym.get_name()))
+            .collect();
+        for sect in sections.iter() {
+            for patch in sect.get_patches() {
+                collect_symbols(&patch.expr, &mut sym_names);
+            }
+        }
+        for assertion in assertions.iter() {
+            collect_symbols(&assertion.expr, &mut sym_names);
+        }
+        let sym_ids: HashMap<_, _> = sym_names
+            .iter()
+            .enumerate()
+            .map(|(id, name)| (Rc::clone(name), id as i32))
+            .collect();
+
+        out.write_all(MAGIC)?;
+        let revision = if had_errors {
+            REVISION | REVISION_HAD_ERRORS
+        } else {
+            REVISION
+        };
+        write_long(&mut out, revision)?;
+        write_long(&mut out, sym_names.len() as i32)?;
+        write_long(&mut out, sections.len() as i32)?;
+
+        // File stack nodes; sources are read without a name, and locations don't keep track of
+        // INCLUDEs nor expansions yet, so everything is attributed to a single root node
+        write_long(&mut out, 1)?;
+        write_long(&mut out, -1)?; // Parent node
+        write_long(&mut out, 0)?; // Parent line
+        write_byte(&mut out, NODE_FILE)?;
+        write_string(&mut out, "???")?;
+
+        for name in &sym_names {
+            write_string(&mut out, name)?;
+            match symbols.get(name) {
+                // Referenced, but not defined in this file
+                None => write_byte(&mut out, SYM_IMPORT)?,
+                Some(sym) => {
+                    let sym_type = if sym.is_exported() {
+                        SYM_EXPORT
+                    } else {
+                        SYM_LOCAL
+                    };
+                    write_byte(&mut out, sym_type)?;
+                    write_long(&mut out, 0)?; // Node
+                    write_long(&mut out, sym.get_line_no() as i32)?;
+                    let (sect_id, value) = match sym.get_label() {
+                        Some((sect_id, offset)) => (sect_id as i32, offset.into()),
+                        None => (-1, sym.get_value().unwrap_or(0)),
+                    };
+                    write_long(&mut out, sect_id)?;
+                    write_long(&mut out, value)?;
+                }
+            }
+        }
+
+        for (sect_id, sect) in sections.iter().enumerate() {
+            write_string(&mut out, sect.get_name())?;
+            write_long(&mut out, sect.get_size() as i32)?;
+            let flags = if sect.is_fragment() { SECT_FRAGMENT } else { 0 };
+            write_byte(&mut out, sect_type_id(sect.get_type()) | flags)?;
+            write_long(&mut out, sect.get_org().map_or(-1, i32::from))?;
+            write_long(&mut out, sect.get_bank().map_or(-1, |bank| bank as i32))?;
+            let (align, align_ofs) = sect.get_align().unwrap_or((0, 0));
+            write_byte(&mut out, align)?;
+            write_long(&mut out, align_ofs.into())?;
+
+            if sect.can_contain_data() {
+                out.write_all(sect.get_data())?;
+                write_long(&mut out, sect.get_patches().len() as i32)?;
+                for patch in sect.get_patches() {
+                    write_long(&mut out, 0)?; // Node
+                    write_long(&mut out, patch.line_no as i32)?;
+                    write_long(&mut out, patch.offset.into())?;
+                    write_long(&mut out, sect_id as i32)?;
+                    // `jr`'s operand follows its opcode, but the linker expects the instruction's address
+                    let pc_offset = match patch.patch_type {
+                        PatchType::Jr => patch.offset - 1,
+                        _ => patch.offset,
+                    };
+                    write_long(&mut out, pc_offset.into())?;
+                    write_byte(&mut out, patch_type_id(patch.patch_type))?;
+
+                    let mut rpn = Vec::new();
+                    write_patch_rpn(&patch.expr, &sym_ids, &mut rpn);
+                    write_long(&mut out, rpn.len() as i32)?;
+                    out.write_all(&rpn)?;
+                }
+            }
+        }
+
+        // Assertions are written like patches, with the assertion type instead of the patch's
+        write_long(&mut out, assertions.len() as i32)?;
+        for assertion in assertions.iter() {
+            let (sect_id, offset) = match assertion.pc {
+                Some((sect_id, offset)) => (sect_id as i32, offset.into()),
+                None => (-1, 0),
+            };
+            let line_no = assertion.location.as_ref().map_or(0, Location::line_no);
+            write_long(&mut out, 0)?; // Node
+            write_long(&mut out, line_no as i32)?;
+            write_long(&mut out, offset)?;
+            write_long(&mut out, sect_id)?;
+            write_long(&mut out, offset)?; // PC offset
+            write_byte(&mut out, assert_type_id(&assertion.assert_type))?;
+
+            let mut rpn = Vec::new();
+            write_rpn(&assertion.expr, &sym_ids, &mut rpn);
+            write_long(&mut out, rpn.len() as i32)?;
+            out.write_all(&rpn)?;
+            write_string(&mut out, assertion.msg.as_deref().unwrap_or(""))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+use crate::AssemblerError;
+use std::convert::TryFrom;
+
+// Options that can be changed from within the source, using `OPT`
+#[derive(Debug, Clone)]
+pub struct AssemblerOptions {
+    pub bin_digits: [char; 2],
+    pub gfx_digits: [char; 4],
+    pub fill_byte: u8,
+    pub pad_byte: u8,
+    // `stop` is followed by a padding byte unless this is set
+    pub short_stop: bool,
+    // Also accept C-style `0x` and `0b` number prefixes
+    pub c_prefixes: bool,
+    // How many of a fixed-point number's bits are fractional
+    pub fixed_precision: u8,
+}
+
+impl Default for AssemblerOptions {
+    fn default() -> Self {
+        Self {
+            bin_digits: ['0', '1'],
+            gfx_digits: ['0', '1', '2', '3'],
+            fill_byte: 0,
+            pad_byte: 0,
+            short_stop: false,
+            c_prefixes: false,
+            fixed_precision: 16,
+        }
+    }
+}
+
+// Parses a number option's value, e.g. `$2A`
+fn parse_number(string: &str) -> Option<i32> {
+    let (radix, digits) = match string.chars().next()? {
+        '$' => (16, &string[1..]),
+        '%' => (2, &string[1..]),
+        '&' => (8, &string[1..]),
+        _ => (10, string),
+    };
+    // Allow the full unsigned range, as the lexer does
+    u32::from_str_radix(digits, radix)
+        .ok()
+        .map(|val| val as i32)
+}
+
+fn parse_byte(string: &str) -> Option<u8> {
+    u8::try_from(parse_number(string)?).ok()
+}
+
+// Digit sets must be made of exactly as many distinct characters as their radix
+fn parse_digits(string: &str, radix: usize) -> Option<Vec<char>> {
+    let digits: Vec<char> = string.chars().collect();
+    if digits.len() != radix || (1..radix).any(|i| digits[..i].contains(&digits[i])) {
+        return None;
+    }
+    Some(digits)
+}
+
+impl AssemblerOptions {
+    // Applies a single option, e.g. `z$FF`; flags are cleared by prefixing them with `!`
+    pub fn set(&mut self, spec: &str) -> Result<(), AssemblerError> {
+        let mut chars = spec.chars();
+        let bad_option = || AssemblerError::BadOption(spec.to_string());
+
+        match chars.next() {
+            Some('b') => {
+                let digits = parse_digits(chars.as_str(), 2).ok_or_else(bad_option)?;
+                self.bin_digits.copy_from_slice(&digits);
+            }
+            Some('g') => {
+                let digits = parse_digits(chars.as_str(), 4).ok_or_else(bad_option)?;
+                self.gfx_digits.copy_from_slice(&digits);
+            }
+            // Written like `Q.8`, the dot being optional
+            Some('Q') => {
+                let digits = chars.as_str();
+                let bits = digits.strip_prefix('.').unwrap_or(digits).parse().ok();
+                self.fixed_precision = bits
+                    .filter(|bits| (1..=31).contains(bits))
+                    .ok_or_else(bad_option)?;
+            }
+            Some('p') => self.pad_byte = parse_byte(chars.as_str()).ok_or_else(bad_option)?,
+            Some('z') => self.fill_byte = parse_byte(chars.as_str()).ok_or_else(bad_option)?,
+            Some('c') if chars.as_str().is_empty() => self.c_prefixes = true,
+            Some('s') if chars.as_str().is_empty() => self.short_stop = true,
+            Some('!') => match chars.as_str() {
+                "c" => self.c_prefixes = false,
+                "s" => self.short_stop = false,
+                _ => return Err(bad_option()),
+            },
+            _ => return Err(bad_option()),
+        }
+        Ok(())
+    }
+}
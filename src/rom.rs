@@ -0,0 +1,36 @@
+use crate::{Assembler, AssemblerError};
+use std::io::{self, Write};
+
+const BANK_SIZE: usize = 0x4000;
+
+impl Assembler<'_> {
+    // Writes a ROM image directly, which is only possible if no section needs the linker
+    // Unused space is filled with the `OPT p` byte, up to the end of the last bank
+    pub fn write_rom(&self, mut out: impl Write) -> io::Result<()> {
+        let sections = self.sections.borrow();
+        let needs_linking = |name: &str| {
+            let err = AssemblerError::NeedsLinking(name.to_string());
+            io::Error::new(io::ErrorKind::InvalidInput, err.to_string())
+        };
+
+        let mut rom = vec![self.options().pad_byte; BANK_SIZE];
+        for sect in sections.iter().filter(|sect| sect.can_contain_data()) {
+            // ROMX banks follow each other in the image, each mapped at $4000
+            let org = match (sect.get_org(), sect.get_bank()) {
+                (Some(org), Some(bank)) => bank as usize * BANK_SIZE + usize::from(org) % BANK_SIZE,
+                _ => return Err(needs_linking(sect.get_name())),
+            };
+            if !sect.get_patches().is_empty() {
+                return Err(needs_linking(sect.get_name()));
+            }
+
+            let end = org + sect.get_size();
+            if end > rom.len() {
+                let len = end.div_ceil(BANK_SIZE) * BANK_SIZE;
+                rom.resize(len, self.options().pad_byte);
+            }
+            rom[org..end].copy_from_slice(sect.get_data());
+        }
+        out.write_all(&rom)
+    }
+}
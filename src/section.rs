@@ -1,4 +1,14 @@
-#[derive(Debug)]
+use crate::expression::Expression;
+use crate::AssemblerError;
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+// Code emitted before any `SECTION` goes there; the parens make it stand out in listings and maps
+pub const IMPLICIT_NAME: &str = "(implicit)";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Type {
     Rom0,
     Romx,
@@ -10,7 +20,313 @@ pub enum Type {
     Hram,
 }
 
+impl Type {
+    // Start address and size of the memory region sections of this type are placed in
+    pub fn region(&self) -> (u16, usize) {
+        match self {
+            Self::Rom0 => (0x0000, 0x4000),
+            Self::Romx => (0x4000, 0x4000),
+            Self::Vram => (0x8000, 0x2000),
+            Self::Sram => (0xa000, 0x2000),
+            Self::Wram0 => (0xc000, 0x1000),
+            Self::Wramx => (0xd000, 0x1000),
+            Self::Oam => (0xfe00, 0xa0),
+            Self::Hram => (0xff80, 0x7f),
+        }
+    }
+
+    pub fn address_range(&self) -> RangeInclusive<u16> {
+        let (start, size) = self.region();
+        start..=start + (size - 1) as u16
+    }
+
+    // Banks that sections of this type may be placed in, if the type is banked at all
+    pub fn banks(&self) -> Option<RangeInclusive<u32>> {
+        match self {
+            Self::Romx => Some(1..=511),
+            Self::Vram => Some(0..=1),
+            Self::Sram => Some(0..=15),
+            Self::Wramx => Some(1..=7),
+            Self::Rom0 | Self::Wram0 | Self::Oam | Self::Hram => None,
+        }
+    }
+
+    pub fn is_banked(&self) -> bool {
+        self.banks().is_some()
+    }
+
+    // The lowest bank, which unbanked types are always in
+    pub fn default_bank(&self) -> u32 {
+        self.banks().map_or(0, |banks| *banks.start())
+    }
+
+    pub fn is_rom(&self) -> bool {
+        matches!(self, Self::Rom0 | Self::Romx)
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        let name = match self {
+            Self::Rom0 => "ROM0",
+            Self::Romx => "ROMX",
+            Self::Vram => "VRAM",
+            Self::Sram => "SRAM",
+            Self::Wram0 => "WRAM0",
+            Self::Wramx => "WRAMX",
+            Self::Oam => "OAM",
+            Self::Hram => "HRAM",
+        };
+        write!(fmt, "{}", name)
+    }
+}
+
+// Case-insensitive, like the section types in the source
+impl FromStr for Type {
+    type Err = AssemblerError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        [
+            Self::Rom0,
+            Self::Romx,
+            Self::Vram,
+            Self::Sram,
+            Self::Wram0,
+            Self::Wramx,
+            Self::Oam,
+            Self::Hram,
+        ]
+        .iter()
+        .copied()
+        .find(|sect_type| sect_type.to_string().eq_ignore_ascii_case(name))
+        .ok_or_else(|| AssemblerError::BadSectionType(name.to_string()))
+    }
+}
+
+// Attributes that may follow a section's type, e.g. `BANK[2]`
+#[derive(Debug)]
+pub enum Attr {
+    Bank(i32),
+    Align(i32, i32),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Attrs {
+    sect_type: Type,
+    org: Option<u16>,
+    bank: Option<u32>,
+    // How many low bits of the address are fixed, and their value
+    align: Option<(u8, u16)>,
+    // Fragments of the same section are concatenated by the linker
+    fragment: bool,
+}
+
+impl Attrs {
+    pub fn new(sect_type: Type, org: Option<i32>, fragment: bool) -> Result<Self, AssemblerError> {
+        let org = match org {
+            Some(org) => match u16::try_from(org) {
+                Ok(addr) if sect_type.address_range().contains(&addr) => Some(addr),
+                _ => return Err(AssemblerError::BadOrg(org, sect_type)),
+            },
+            None => None,
+        };
+        Ok(Self {
+            sect_type,
+            org,
+            bank: None,
+            align: None,
+            fragment,
+        })
+    }
+
+    pub fn set(&mut self, attr: Attr) -> Result<(), AssemblerError> {
+        match attr {
+            Attr::Bank(bank) => {
+                let banks = self
+                    .sect_type
+                    .banks()
+                    .ok_or(AssemblerError::UnbankedSection(self.sect_type))?;
+                match u32::try_from(bank) {
+                    Ok(bank) if banks.contains(&bank) => self.bank = Some(bank),
+                    _ => return Err(AssemblerError::BadBank(bank, self.sect_type)),
+                }
+            }
+            Attr::Align(bits, ofs) => {
+                if !(0..=16).contains(&bits) {
+                    return Err(AssemblerError::BadAlign(bits));
+                }
+                if !(0..1 << bits).contains(&ofs) {
+                    return Err(AssemblerError::BadAlignOffset(ofs));
+                }
+                let mask = ((1_u32 << bits) - 1) as u16;
+                if self.org.is_some_and(|org| org & mask != ofs as u16) {
+                    return Err(AssemblerError::OrgAlignMismatch);
+                }
+                self.align = Some((bits as u8, ofs as u16));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum PatchType {
+    Byte,
+    Word,
+    Long,
+    Jr,
+}
+
+impl PatchType {
+    fn size(self) -> usize {
+        match self {
+            Self::Byte | Self::Jr => 1,
+            Self::Word => 2,
+            Self::Long => 4,
+        }
+    }
+}
+
+// A value that couldn't be computed at assembly time, which the linker will have to write
 #[derive(Debug)]
-struct Attrs {
-    field: Type,
+pub struct Patch {
+    pub offset: u16,
+    pub patch_type: PatchType,
+    pub expr: Expression,
+    pub line_no: u32,
+}
+
+// A snapshot of a section's layout, e.g. for external linkers or analyzers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectionInfo {
+    pub name: String,
+    pub sect_type: Type,
+    pub bank: Option<u32>,
+    // Only known for sections with a fixed address
+    pub start: Option<u16>,
+    pub len: usize,
+}
+
+impl SectionInfo {
+    pub fn is_fixed(&self) -> bool {
+        self.start.is_some()
+    }
+}
+
+#[derive(Debug)]
+pub struct Section {
+    name: String,
+    attrs: Attrs,
+    size: usize,
+    data: Vec<u8>,
+    patches: Vec<Patch>,
+}
+
+impl Section {
+    // === Constructor ===
+
+    pub fn new(name: String, attrs: Attrs) -> Self {
+        Self {
+            name,
+            attrs,
+            size: 0,
+            data: Vec::new(),
+            patches: Vec::new(),
+        }
+    }
+
+    // === Getters ===
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_type(&self) -> &Type {
+        &self.attrs.sect_type
+    }
+
+    pub fn is_fragment(&self) -> bool {
+        self.attrs.fragment
+    }
+
+    // Whether a declaration with these attributes continues this section
+    pub fn continues_with(&self, attrs: &Attrs) -> bool {
+        self.attrs.fragment && self.attrs == *attrs
+    }
+
+    pub fn get_org(&self) -> Option<u16> {
+        self.attrs.org
+    }
+
+    pub fn get_bank(&self) -> Option<u32> {
+        if self.attrs.sect_type.is_banked() {
+            self.attrs.bank
+        } else {
+            Some(self.attrs.sect_type.default_bank())
+        }
+    }
+
+    pub fn get_align(&self) -> Option<(u8, u16)> {
+        self.attrs.align
+    }
+
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+
+    pub fn get_data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn get_patches(&self) -> &[Patch] {
+        &self.patches
+    }
+
+    pub fn info(&self) -> SectionInfo {
+        SectionInfo {
+            name: self.name.clone(),
+            sect_type: self.attrs.sect_type,
+            bank: self.get_bank(),
+            start: self.attrs.org,
+            len: self.size,
+        }
+    }
+
+    pub fn fits_in_region(&self) -> bool {
+        let (start, size) = self.attrs.sect_type.region();
+        match self.attrs.org {
+            Some(org) => org >= start && usize::from(org - start) + self.get_size() <= size,
+            None => self.get_size() <= size,
+        }
+    }
+
+    pub fn can_contain_data(&self) -> bool {
+        self.attrs.sect_type.is_rom()
+    }
+
+    // === Actions ===
+
+    pub fn emit_bytes(&mut self, bytes: &[u8]) {
+        debug_assert!(self.can_contain_data());
+        self.data.extend_from_slice(bytes);
+        self.size += bytes.len();
+    }
+
+    pub fn emit_patch(&mut self, patch_type: PatchType, expr: Expression, line_no: u32) {
+        debug_assert!(self.can_contain_data());
+        self.patches.push(Patch {
+            offset: self.data.len() as u16,
+            patch_type,
+            expr,
+            line_no,
+        });
+        // Placeholder bytes, overwritten by the linker
+        self.data.resize(self.data.len() + patch_type.size(), 0);
+        self.size += patch_type.size();
+    }
+
+    // Grows the section without giving it any data, e.g. for `LOAD` blocks
+    pub fn advance(&mut self, len: usize) {
+        self.size += len;
+    }
 }
@@ -1,18 +1,32 @@
+use crate::section::Section;
 use crate::AssemblerError;
+use std::cell::Cell;
+use std::io::{self, Read, Write};
 use std::rc::Rc;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Symbol {
     name: Rc<String>,
     val: Type,
     exported: bool,
+    referenced: Cell<bool>,
+    // 0 for symbols not defined by the source, e.g. with `-D`
+    line_no: u32,
 }
 
-#[derive(Debug)]
+// Tags of the serialized types
+const TYPE_EQU: u8 = 0;
+const TYPE_EQUS: u8 = 1;
+const TYPE_LABEL: u8 = 2;
+const TYPE_MACRO: u8 = 3;
+const TYPE_SET: u8 = 4;
+
+#[derive(Debug, Clone)]
 enum Type {
     Equ(i32),
     Equs(String),
-    Label(i32), // TODO: actually a section + offset
+    Label { section: usize, offset: u16 },
+    Macro(Rc<String>),
     Set(i32),
 }
 
@@ -24,6 +38,8 @@ impl Symbol {
             name: Rc::new(name),
             val: Type::Equ(val),
             exported: false,
+            referenced: Cell::new(false),
+            line_no: 0,
         }
     }
 
@@ -32,14 +48,28 @@ impl Symbol {
             name: Rc::new(name),
             val: Type::Equs(val),
             exported: false,
+            referenced: Cell::new(false),
+            line_no: 0,
+        }
+    }
+
+    pub fn new_label(name: String, section: usize, offset: u16) -> Self {
+        Symbol {
+            name: Rc::new(name),
+            val: Type::Label { section, offset },
+            exported: false,
+            referenced: Cell::new(false),
+            line_no: 0,
         }
     }
 
-    pub fn new_label(name: String, val: i32) -> Self {
+    pub fn new_macro(name: String, body: String) -> Self {
         Symbol {
             name: Rc::new(name),
-            val: Type::Label(val),
+            val: Type::Macro(Rc::new(body)),
             exported: false,
+            referenced: Cell::new(false),
+            line_no: 0,
         }
     }
 
@@ -48,6 +78,8 @@ impl Symbol {
             name: Rc::new(name),
             val: Type::Set(val),
             exported: false,
+            referenced: Cell::new(false),
+            line_no: 0,
         }
     }
 
@@ -57,22 +89,58 @@ impl Symbol {
         &self.name
     }
 
-    pub fn get_str<'a>(&'a self) -> Option<&'a String> {
+    pub fn get_str(&self) -> Option<&String> {
         match &self.val {
-            Type::Equs(string) => Some(&string),
+            Type::Equs(string) => Some(string),
             _ => None,
         }
     }
 
+    // Labels have no value until their section is placed, which is up to the linker
     pub fn get_value(&self) -> Option<i32> {
         match self.val {
             Type::Equ(v) => Some(v),
-            Type::Label(v) => Some(v),
             Type::Set(v) => Some(v),
             _ => None,
         }
     }
 
+    // Like `get_value`, but labels in sections with a fixed address have one as well
+    pub fn resolve(&self, sections: &[Section]) -> Option<i32> {
+        match self.val {
+            Type::Label { section, offset } => sections[section]
+                .get_org()
+                .map(|org| i32::from(org) + i32::from(offset)),
+            _ => self.get_value(),
+        }
+    }
+
+    pub fn get_label(&self) -> Option<(usize, u16)> {
+        match self.val {
+            Type::Label { section, offset } => Some((section, offset)),
+            _ => None,
+        }
+    }
+
+    pub fn get_macro(&self) -> Option<&Rc<String>> {
+        match &self.val {
+            Type::Macro(body) => Some(body),
+            _ => None,
+        }
+    }
+
+    pub fn is_exported(&self) -> bool {
+        self.exported
+    }
+
+    pub fn is_referenced(&self) -> bool {
+        self.referenced.get()
+    }
+
+    pub fn get_line_no(&self) -> u32 {
+        self.line_no
+    }
+
     pub fn set_value(&mut self, val: i32) {
         self.val = match self.val {
             Type::Equ(_) => Type::Equ(val),
@@ -85,10 +153,138 @@ impl Symbol {
 
     pub fn redefine(&mut self, other: Self) -> Result<(), AssemblerError> {
         debug_assert_eq!(self.name, other.name);
-        unimplemented!();
+
+        match (&self.val, &other.val) {
+            // Only `SET` symbols may be redefined, and only by another `SET`
+            (Type::Set(_), Type::Set(_)) => {
+                self.val = other.val;
+                self.line_no = other.line_no;
+                Ok(())
+            }
+            _ => Err(self.redef_error(&other)),
+        }
+    }
+
+    // Common mistakes get a more specific error
+    fn redef_error(&self, other: &Self) -> AssemblerError {
+        let name = self.name.to_string();
+        match (&self.val, &other.val) {
+            (Type::Set(_), Type::Equ(_)) => AssemblerError::RedefSetAsEqu(name),
+            (Type::Equ(_), Type::Set(_)) => AssemblerError::RedefEquAsSet(name),
+            (Type::Label { .. }, _) => AssemblerError::RedefLabel(name),
+            _ => AssemblerError::SymbolRedef,
+        }
+    }
+
+    // `REDEF` may change the value of `EQU` and `EQUS` symbols, but not their type
+    pub fn force_redefine(&mut self, other: Self) -> Result<(), AssemblerError> {
+        debug_assert_eq!(self.name, other.name);
+
+        match (&self.val, &other.val) {
+            (Type::Equ(_), Type::Equ(_)) | (Type::Equs(_), Type::Equs(_)) => {
+                self.val = other.val;
+                self.line_no = other.line_no;
+                Ok(())
+            }
+            _ => Err(self.redef_error(&other)),
+        }
     }
 
     pub fn export(&mut self) {
         self.exported = true;
     }
+
+    pub fn mark_referenced(&self) {
+        self.referenced.set(true);
+    }
+
+    pub fn set_line_no(&mut self, line_no: u32) {
+        self.line_no = line_no;
+    }
+
+    // === Serialization, e.g. to cache symbol tables between builds ===
+
+    // Whether the symbol is referenced and where it was defined only matter during assembly,
+    // so they're not written
+    pub fn write(&self, mut out: impl Write) -> io::Result<()> {
+        write_string(&mut out, &self.name)?;
+        match &self.val {
+            Type::Equ(val) => {
+                out.write_all(&[TYPE_EQU])?;
+                out.write_all(&val.to_le_bytes())?;
+            }
+            Type::Equs(string) => {
+                out.write_all(&[TYPE_EQUS])?;
+                write_string(&mut out, string)?;
+            }
+            Type::Label { section, offset } => {
+                out.write_all(&[TYPE_LABEL])?;
+                out.write_all(&(*section as u32).to_le_bytes())?;
+                out.write_all(&offset.to_le_bytes())?;
+            }
+            Type::Macro(body) => {
+                out.write_all(&[TYPE_MACRO])?;
+                write_string(&mut out, body)?;
+            }
+            Type::Set(val) => {
+                out.write_all(&[TYPE_SET])?;
+                out.write_all(&val.to_le_bytes())?;
+            }
+        }
+        out.write_all(&[self.exported.into()])
+    }
+
+    pub fn read(mut input: impl Read) -> io::Result<Self> {
+        let name = read_string(&mut input)?;
+        let val = match read_array::<1>(&mut input)? {
+            [TYPE_EQU] => Type::Equ(i32::from_le_bytes(read_array(&mut input)?)),
+            [TYPE_EQUS] => Type::Equs(read_string(&mut input)?),
+            [TYPE_LABEL] => Type::Label {
+                section: u32::from_le_bytes(read_array(&mut input)?) as usize,
+                offset: u16::from_le_bytes(read_array(&mut input)?),
+            },
+            [TYPE_MACRO] => Type::Macro(Rc::new(read_string(&mut input)?)),
+            [TYPE_SET] => Type::Set(i32::from_le_bytes(read_array(&mut input)?)),
+            [tag] => return Err(invalid_data(format!("Bad symbol type {}", tag))),
+        };
+        let exported = match read_array::<1>(&mut input)? {
+            [0] => false,
+            [1] => true,
+            [flag] => return Err(invalid_data(format!("Bad export flag {}", flag))),
+        };
+
+        Ok(Symbol {
+            name: Rc::new(name),
+            val,
+            exported,
+            referenced: Cell::new(false),
+            line_no: 0,
+        })
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Strings are length-prefixed, since EQUS and macros may contain anything
+fn write_string(out: &mut impl Write, string: &str) -> io::Result<()> {
+    out.write_all(&(string.len() as u32).to_le_bytes())?;
+    out.write_all(string.as_bytes())
+}
+
+fn read_array<const N: usize>(input: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_string(input: &mut impl Read) -> io::Result<String> {
+    let len = u32::from_le_bytes(read_array(input)?);
+    let mut bytes = Vec::new();
+    input.take(len.into()).read_to_end(&mut bytes)?;
+    if bytes.len() != len as usize {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    String::from_utf8(bytes).map_err(|err| invalid_data(err.to_string()))
 }
@@ -0,0 +1,28 @@
+use crate::Assembler;
+use std::io::{self, Write};
+
+impl Assembler<'_> {
+    // Lists labels in the RGBDS `.sym` format
+    // Only labels whose address is already known (in fixed sections) can be listed
+    pub fn write_sym_file(&self, mut out: impl Write) -> io::Result<()> {
+        let symbols = self.symbols.borrow();
+        let sections = self.sections.borrow();
+
+        let mut labels: Vec<_> = symbols
+            .values()
+            .filter_map(|sym| {
+                let (sect_id, offset) = sym.get_label()?;
+                let sect = &sections[sect_id];
+                let addr = sect.get_org()?.wrapping_add(offset);
+                Some((sect.get_bank()?, addr, sym.get_name()))
+            })
+            .collect();
+        labels.sort();
+
+        writeln!(out, "; File generated by rsgbasm")?;
+        for (bank, addr, name) in labels {
+            writeln!(out, "{:02x}:{:04x} {}", bank, addr, name)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+mod common;
+
+use common::*;
+use rsgbasm::Assembler;
+use std::ops::ControlFlow;
+
+#[test]
+fn charmap_translates_strings() {
+    assert_eq!(bytes("SECTION \"a\", ROM0\ndb \"A\"\n"), [0x41]);
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\nCHARMAP \"A\", $80\ndb \"A\"\n"),
+        [0x80]
+    );
+    // The longest mapping wins, and unmapped characters are left alone
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\nCHARMAP \"A\", $80\nCHARMAP \"AB\", $81, $82\ndb \"AAB\", \"C\"\n"),
+        [0x80, 0x81, 0x82, 0x43]
+    );
+}
+
+#[test]
+fn charmaps_are_named() {
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\nNEWCHARMAP other\nCHARMAP \"A\", 1\ndb \"A\"\nSETCHARMAP main\ndb \"A\"\n"),
+        [1, 0x41]
+    );
+    assert_eq!(
+        diagnostics("SETCHARMAP nope\n"),
+        ["E: Charmap \"nope\" doesn't exist at ???:1:1-1:16"]
+    );
+    assert_eq!(
+        diagnostics("NEWCHARMAP other\nNEWCHARMAP other\n"),
+        ["E: Charmap \"other\" already exists at ???:2:1-2:17"]
+    );
+}
+
+#[test]
+fn charmaps_can_be_set_up_before_assembling() {
+    let callback = |_| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.charmap_add("A", vec![0x80]);
+    asm.new_charmap("alt".to_string(), Some("main")).unwrap();
+    asm.charmap_add("B", vec![0x81]);
+    asm.set_charmap("main".to_string()).unwrap();
+    asm.assemble_append("SECTION \"a\", ROM0\ndb \"AB\"\nSETCHARMAP alt\ndb \"AB\"\n".as_bytes())
+        .unwrap();
+    assert_eq!(asm.error_count(), 0);
+    assert_eq!(*asm.section_bytes("a").unwrap(), [0x80, b'B', 0x80, 0x81]);
+
+    // A full assembly starts over from an empty main charmap
+    asm.assemble_str("SECTION \"a\", ROM0\ndb \"AB\"\nSETCHARMAP alt\n")
+        .unwrap();
+    assert_eq!(asm.error_count(), 1);
+    assert_eq!(*asm.section_bytes("a").unwrap(), *b"AB");
+}
+
+#[test]
+fn pushc_and_popc_restore_the_charmap() {
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\nNEWCHARMAP m2\nCHARMAP \"A\", 2\nSETCHARMAP main\nCHARMAP \"A\", 1\nPUSHC\nSETCHARMAP m2\ndb \"A\"\nPOPC\ndb \"A\"\n"),
+        [2, 1]
+    );
+    assert_eq!(
+        diagnostics("PUSHC\nPOPC\nPOPC\n"),
+        ["E: No entries in the charmap stack at ???:3:1-3:5"]
+    );
+}
+
+#[test]
+fn charmaps_can_inherit_mappings() {
+    assert_eq!(
+        assemble("SECTION \"a\", ROM0\nNEWCHARMAP base\nCHARMAP \"A\", $80\nNEWCHARMAP derived, base\nCHARMAP \"B\", $81\ndb \"AB\"\nSETCHARMAP base\ndb \"AB\"\nNEWCHARMAP x, nope\n"),
+        (
+            vec![0x80, 0x81, 0x80, b'B'],
+            vec!["E: Charmap \"nope\" doesn't exist at ???:9:1-9:19".to_string()]
+        )
+    );
+}
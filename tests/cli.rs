@@ -0,0 +1,68 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+// A scratch directory per test, so that tests running in parallel don't clash
+fn work_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rsgbasm-cli-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn rsgbasm(dir: &PathBuf, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rsgbasm"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn writes_the_object_file() {
+    let dir = work_dir("object");
+    fs::create_dir(dir.join("inc")).unwrap();
+    fs::write(dir.join("inc/defs.inc"), "ONE EQU 1\n").unwrap();
+    fs::write(
+        dir.join("main.asm"),
+        "INCLUDE \"defs.inc\"\nSECTION \"a\", ROM0\ndb ONE, TWO\n",
+    )
+    .unwrap();
+
+    let output = rsgbasm(
+        &dir,
+        &["-I", "inc", "-D", "TWO=2", "-o", "out.o", "main.asm"],
+    );
+    assert!(output.status.success(), "{:?}", output);
+    let obj = fs::read(dir.join("out.o")).unwrap();
+    assert_eq!(&obj[..4], b"RGB9");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn files_are_not_glued_together() {
+    let dir = work_dir("files");
+    // No newline at the end of the first file
+    fs::write(dir.join("a.asm"), "SECTION \"a\", ROM0\nLabel: db 1").unwrap();
+    fs::write(dir.join("b.asm"), "db 2\ndw Label\n").unwrap();
+
+    let output = rsgbasm(&dir, &["-o", "out.o", "a.asm", "b.asm"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(dir.join("out.o").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn reports_failures() {
+    let dir = work_dir("failures");
+    fs::write(dir.join("bad.asm"), "SECTION \"a\", ROM0\nld bc, de\n").unwrap();
+
+    let output = rsgbasm(&dir, &["-o", "out.o", "bad.asm"]);
+    assert!(!output.status.success());
+    assert!(!dir.join("out.o").exists());
+
+    let output = rsgbasm(&dir, &["missing.asm"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Failed to open \"missing.asm\""));
+    fs::remove_dir_all(&dir).unwrap();
+}
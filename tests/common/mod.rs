@@ -0,0 +1,92 @@
+// Helpers shared by the integration tests; not every test file uses all of them
+#![allow(dead_code)]
+
+use rsgbasm::{Assembler, Diagnostic};
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::ops::ControlFlow;
+use std::rc::Rc;
+
+// Diagnostics are collected as "E: ..." or "W: ...", which is easy to compare against
+fn describe(diag: Diagnostic) -> String {
+    match diag {
+        Diagnostic::Warning(warning) => format!("W: {}", warning),
+        Diagnostic::Error(err) => format!("E: {}", err),
+    }
+}
+
+// Assembles `src` with an assembler set up by `configure`, returning what was emitted to
+// section "a" and the diagnostics
+pub fn assemble_with(src: &str, configure: impl FnOnce(&mut Assembler)) -> (Vec<u8>, Vec<String>) {
+    let diags = RefCell::new(Vec::new());
+    let callback = |diag| {
+        diags.borrow_mut().push(describe(diag));
+        ControlFlow::Continue(())
+    };
+    let mut asm = Assembler::new(&callback);
+    configure(&mut asm);
+    asm.assemble_str(src).unwrap();
+    let bytes = asm.section_bytes("a").map(|bytes| bytes.to_vec());
+    drop(asm);
+    (bytes.unwrap_or_default(), diags.into_inner())
+}
+
+pub fn assemble(src: &str) -> (Vec<u8>, Vec<String>) {
+    assemble_with(src, |_| ())
+}
+
+pub fn diagnostics_with(src: &str, configure: impl FnOnce(&mut Assembler)) -> Vec<String> {
+    assemble_with(src, configure).1
+}
+
+pub fn diagnostics(src: &str) -> Vec<String> {
+    diagnostics_with(src, |_| ())
+}
+
+// Same as `assemble`, for sources that must not produce any diagnostics
+pub fn bytes(src: &str) -> Vec<u8> {
+    let (bytes, diags) = assemble(src);
+    assert_eq!(diags, Vec::<String>::new(), "unexpected diagnostics");
+    bytes
+}
+
+// Assembles `src`, returning the symbol file's listing of the labels it defined
+pub fn labels(src: &str) -> Vec<String> {
+    let callback = |_| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble_str(src).unwrap();
+    let mut out = Vec::new();
+    asm.write_sym_file(&mut out).unwrap();
+    drop(asm);
+    String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .filter(|line| !line.starts_with(';'))
+        .map(str::to_string)
+        .collect()
+}
+
+// An output that can still be read after being handed to the assembler
+#[derive(Clone, Default)]
+pub struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuf {
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8(self.contents()).unwrap()
+    }
+}
+
+impl Write for SharedBuf {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn only_one_branch_is_assembled() {
+    let src = "SECTION \"a\", ROM0\nIF 0\ndb 1\nELIF 1\ndb 2\nELIF 1\ndb 3\nELSE\ndb 4\nENDC\n";
+    assert_eq!(bytes(src), [2]);
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\nIF 0\ndb 1\nELSE\ndb 2\nENDC\n"),
+        [2]
+    );
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\nIF 1\ndb 1\nELSE\ndb 2\nENDC\n"),
+        [1]
+    );
+}
+
+#[test]
+fn skipped_branches_are_not_assembled() {
+    // Nor are the conditions of later branches checked once one was taken
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\nIF 0\nIF 1\ndb 5\nENDC\nbad syntax (\nELSE\ndb 6\nENDC\nIF 1\ndb 7\nELIF undefined\ndb 8\nENDC\n"),
+        [6, 7]
+    );
+}
+
+#[test]
+fn branches_must_be_inside_if() {
+    assert_eq!(
+        diagnostics("ELSE\nENDC\nELIF 1\n"),
+        [
+            "E: Found ELSE outside of an IF construct at ???:1:1-1:5",
+            "E: Found ENDC outside of an IF construct at ???:2:1-2:5",
+            "E: Found ELIF outside of an IF construct at ???:3:1-3:7",
+        ]
+    );
+    assert_eq!(
+        diagnostics("IF 0\ndb 1\n"),
+        ["E: Unterminated IF construct (missing ENDC)"]
+    );
+}
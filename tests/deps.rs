@@ -0,0 +1,31 @@
+use rsgbasm::Assembler;
+use std::ops::ControlFlow;
+
+fn deps(src: &str, target: &str) -> String {
+    let callback = |_| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble_str(src).unwrap();
+    let mut out = Vec::new();
+    asm.write_deps(target, &mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn lists_included_files() {
+    // Missing files are still listed, even though INCBIN fails to read them
+    assert_eq!(
+        deps(
+            "SECTION \"a\", ROM0\nINCLUDE \"a.inc\"\nINCLUDE \"b.inc\"\nINCLUDE \"a.inc\"\nINCBIN \"$data.bin\"\n",
+            "out.o"
+        ),
+        "out.o: a.inc b.inc $$data.bin\n"
+    );
+}
+
+#[test]
+fn escapes_special_characters() {
+    assert_eq!(
+        deps("SECTION \"a\", ROM0\nINCLUDE \"my #1.inc\"\n", "my out.o"),
+        "my\\ out.o: my\\ \\#1.inc\n"
+    );
+}
@@ -0,0 +1,212 @@
+mod common;
+
+use common::*;
+use rsgbasm::{Assembler, Diagnostic, ErrorKind, WarningClass};
+use std::cell::{Cell, RefCell};
+use std::ops::ControlFlow;
+use std::rc::{Rc, Weak};
+
+#[test]
+fn errors_point_at_their_line() {
+    assert_eq!(
+        diagnostics("SECTION \"a\", ROM0\nLbl:\n  nop\nSETCHARMAP nope\n"),
+        ["E: Charmap \"nope\" doesn't exist at ???:4:1-4:16"]
+    );
+}
+
+#[test]
+fn parsing_resumes_after_errors() {
+    // What the parser expected instead isn't interesting here
+    let diags: Vec<_> =
+        diagnostics("SECTION \"a\", ROM0\nld a, ]\nnop\nfoo bar baz\ndb 1 2\nnop\n")
+            .into_iter()
+            .map(|diag| diag.split("; expected").next().unwrap().to_string())
+            .collect();
+    assert_eq!(
+        diags,
+        [
+            "E: Unexpected ] at ???:2:7-2:8",
+            "E: Unexpected identifier at ???:4:5-4:8",
+            "E: Unexpected number at ???:5:6-5:7",
+        ]
+    );
+}
+
+#[test]
+fn warning_classes_can_be_disabled() {
+    let src = "SECTION \"a\", ROM0\ndb 256\n";
+    assert_eq!(diagnostics(src), ["W: Expression 256 must be 8-bit"]);
+    assert_eq!(
+        diagnostics_with(src, |asm| asm.disable_warning(WarningClass::Truncation)),
+        Vec::<String>::new()
+    );
+}
+
+#[test]
+fn warnings_can_be_promoted_to_errors() {
+    let src = "SECTION \"a\", ROM0\ndb 256\n";
+    assert_eq!(
+        diagnostics_with(src, |asm| asm.set_warnings_as_errors(true)),
+        ["E: Expression 256 must be 8-bit (treated as error)"]
+    );
+    assert_eq!(
+        diagnostics_with(src, |asm| asm
+            .set_warning_as_error(WarningClass::Truncation, true)),
+        ["E: Expression 256 must be 8-bit (treated as error)"]
+    );
+    assert_eq!(
+        diagnostics_with(src, |asm| asm
+            .set_warning_as_error(WarningClass::UnusedSymbol, true)),
+        ["W: Expression 256 must be 8-bit"]
+    );
+}
+
+#[test]
+fn errors_can_be_rendered_with_their_source() {
+    let src = "SECTION \"a\", ROM0\n\tld a, b c\n db 1 / 0\n";
+    let rendered = RefCell::new(String::new());
+    let callback = |diag| {
+        if let Diagnostic::Error(err) = diag {
+            rendered.borrow_mut().push_str(&err.render_with_source(src));
+        }
+        ControlFlow::Continue(())
+    };
+    Assembler::new(&callback).assemble_str(src).unwrap();
+    assert_eq!(
+        rendered.into_inner(),
+        concat!(
+            "error: Unexpected c at ???:2:17-2:18; expected \"\\n\" or \",\"\n",
+            "  |\n",
+            "2 | \tld a, b c\n",
+            "  | \t        ^\n",
+            "error: Division by zero at ???:3:5-3:10\n",
+            "  |\n",
+            "3 |  db 1 / 0\n",
+            "  |     ^^^^^\n",
+        )
+    );
+}
+
+#[test]
+fn callbacks_can_query_the_location() {
+    let asm_slot = RefCell::new(Weak::<Assembler>::new());
+    let lines = RefCell::new(Vec::new());
+    let callback = |_: Diagnostic| {
+        let asm = asm_slot.borrow().upgrade().unwrap();
+        lines
+            .borrow_mut()
+            .push(asm.current_location().map(|loc| loc.line_no()));
+        ControlFlow::Continue(())
+    };
+    let asm = Rc::new(Assembler::new(&callback));
+    *asm_slot.borrow_mut() = Rc::downgrade(&asm);
+    asm.assemble_str("SECTION \"a\", ROM0\n nop\n db 1 / 0\n\n warn \"hi\"\n")
+        .unwrap();
+    assert_eq!(*lines.borrow(), [Some(3), Some(5)]);
+    assert!(asm.current_location().is_none());
+}
+
+#[test]
+fn unused_locals_are_reported() {
+    let src = "SECTION \"a\", ROM0\nMain:\n jr .used\n.used:\n.unused:\n.exp::\nNext:\n";
+    assert_eq!(
+        diagnostics(src),
+        ["W: Symbol \"Main.unused\" is never referenced"]
+    );
+    assert_eq!(
+        diagnostics_with(src, |asm| asm.disable_warning(WarningClass::UnusedSymbol)),
+        Vec::<String>::new()
+    );
+}
+
+#[test]
+fn callbacks_can_abort_assembly() {
+    let seen = Cell::new(0);
+    let callback = |_: Diagnostic| {
+        seen.set(seen.get() + 1);
+        if seen.get() == 2 {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    };
+    let asm = Assembler::new(&callback);
+    asm.assemble_str("SECTION \"a\", ROM0\n db 1/0\n db 2/0, 3/0\n db 4/0\n")
+        .unwrap();
+    assert_eq!(seen.get(), 2);
+}
+
+#[test]
+fn errors_are_capped() {
+    let src = "SECTION \"a\", ROM0\n".to_string() + &" db 1/0\n".repeat(10);
+    let errors = diagnostics_with(&src, |asm| asm.set_max_errors(3));
+    assert_eq!(errors.len(), 4);
+    assert_eq!(errors[3], "E: Assembly aborted after 3 errors");
+}
+
+#[test]
+fn errors_expose_structured_data() {
+    let found = RefCell::new(Vec::new());
+    let callback = |diag: Diagnostic| {
+        if let Diagnostic::Error(err) = diag {
+            found.borrow_mut().push((
+                err.kind(),
+                err.location().map(|loc| (loc.line_no(), loc.col_no())),
+                err.expected_tokens(),
+            ));
+        }
+        ControlFlow::Continue(())
+    };
+    let asm = Assembler::new(&callback);
+    asm.assemble_str("SECTION \"a\", ROM0\n ld a, b c\n db 1/0\n")
+        .unwrap();
+    drop(asm);
+    assert_eq!(
+        *found.borrow(),
+        [
+            (
+                ErrorKind::UnexpectedToken,
+                Some((2, 10)),
+                vec!["\"\\n\"".to_string(), "\",\"".to_string()]
+            ),
+            (ErrorKind::User, Some((3, 5)), vec![]),
+        ]
+    );
+}
+
+#[test]
+fn continued_lines_keep_physical_locations() {
+    let errors =
+        diagnostics("SECTION \"a\", ROM0\nld b, 1 + \\ \n  $100 +\\\r\n 2\nld a, 1 \\ x\n");
+    assert_eq!(
+        errors[..2],
+        [
+            "E: Value 259 doesn't fit in 8 bits at ???:2:1-4:3",
+            "E: Begun line continuation, but encountered character 'x' at ???:5:11-5:12",
+        ]
+    );
+}
+
+#[test]
+fn columns_follow_the_tab_width() {
+    let src = "\tfoo\n";
+    for (width, col) in [(8, 9), (4, 5), (1, 2)] {
+        let cols = RefCell::new(Vec::new());
+        let callback = |diag| {
+            if let Diagnostic::Error(err) = diag {
+                cols.borrow_mut()
+                    .push(err.location().map(|loc| loc.col_no()));
+                assert_eq!(
+                    err.render_with_source_tabs(src, width).lines().last(),
+                    Some("  | \t^^^")
+                );
+            }
+            ControlFlow::Continue(())
+        };
+        let mut asm = Assembler::new(&callback);
+        asm.set_tab_width(width);
+        asm.assemble_str(src).unwrap();
+        drop(asm);
+        assert_eq!(*cols.borrow(), [Some(col)]);
+    }
+}
@@ -0,0 +1,107 @@
+mod common;
+
+use common::*;
+use rsgbasm::Assembler;
+
+#[test]
+fn assert_checks_constant_conditions() {
+    assert_eq!(
+        diagnostics("ASSERT 1 == 1\nASSERT 1 == 1, \"fine\"\n"),
+        Vec::<String>::new()
+    );
+    assert_eq!(
+        diagnostics("ASSERT 1 == 2, \"nope\"\nASSERT 0\n"),
+        [
+            "E: Assertion failure: nope at ???:1:1-1:22",
+            "E: Assertion failure at ???:2:1-2:9",
+        ]
+    );
+}
+
+#[test]
+fn assert_types_follow_a_comma() {
+    assert_eq!(
+        diagnostics("ASSERT WARN, 1 == 2, \"x\"\nASSERT FAIL, 0\nSTATIC_ASSERT WARN, 0\n"),
+        [
+            "W: Assertion failure: x",
+            "E: Assertion failure at ???:2:1-2:15",
+            "W: Assertion failure",
+        ]
+    );
+    // Fatal assertions stop assembly right away
+    assert_eq!(
+        diagnostics("ASSERT FATAL, 1 == 2, \"nope\"\nASSERT 0\n"),
+        ["E: Assertion failure: nope at ???:1:1-1:29"]
+    );
+    assert_eq!(diagnostics("ASSERT WARN 1 == 0\n").len(), 1);
+}
+
+#[test]
+fn assert_defers_unknown_conditions() {
+    assert_eq!(
+        diagnostics("SECTION \"a\", ROM0\nASSERT Label < $8000\nLabel:\n"),
+        Vec::<String>::new()
+    );
+}
+
+#[test]
+fn warn_and_fail_report_their_message() {
+    // `FAIL` stops the assembly, so the last line isn't assembled
+    assert_eq!(
+        assemble(
+            "SECTION \"a\", ROM0\nX EQU 5\nWARN \"x is {d:X}\"\ndb 1\nFAIL \"stop {X}\"\ndb 2\n"
+        ),
+        (
+            vec![1],
+            vec![
+                "W: x is 5".to_string(),
+                "E: stop $5 at ???:5:1-5:16".to_string(),
+            ]
+        )
+    );
+}
+
+#[test]
+fn includes_can_be_served_from_memory() {
+    let configure = |asm: &mut Assembler| {
+        asm.set_include_resolver(|path| match path {
+            "foo.asm" => Some(b"FOO EQU 5\ndb FOO\nINCLUDE \"bar.asm\"".to_vec()),
+            "bar.asm" => Some(b"db 6".to_vec()),
+            "data.bin" => Some(vec![1, 2, 3]),
+            _ => None,
+        })
+    };
+    assert_eq!(
+        assemble_with(
+            "SECTION \"a\", ROM0\nINCLUDE \"foo.asm\"\ndb FOO + 2\nINCBIN \"data.bin\"\n",
+            configure
+        ),
+        (vec![5, 6, 7, 1, 2, 3], vec![])
+    );
+}
+
+#[test]
+fn incbin_slices_are_checked() {
+    let path = std::env::temp_dir().join(format!("rsgbasm-incbin-{}.bin", std::process::id()));
+    std::fs::write(&path, (0..16).collect::<Vec<u8>>()).unwrap();
+    let path = path.to_str().unwrap().to_string();
+
+    let slices = bytes(&format!(
+        "SECTION \"a\", ROM0\nSTART EQU 2\nTILE_SIZE EQU 1\nINCBIN \"{0}\", START, TILE_SIZE * 4\nINCBIN \"{0}\", 14\nINCBIN \"{0}\", 16\n",
+        path
+    ));
+    let errors = diagnostics(&format!(
+        "SECTION \"a\", ROM0\nINCBIN \"{0}\", 10, 8\nINCBIN \"{0}\", -1\nINCBIN \"{0}\", 0, Lbl\nLbl:\n",
+        path
+    ));
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(slices, [2, 3, 4, 5, 14, 15]);
+    assert_eq!(errors.len(), 3);
+    assert!(errors[0].starts_with(&format!(
+        "E: INCBIN range ends at byte 18, but \"{}\" is only 16 bytes long",
+        path
+    )));
+    assert!(errors[1].starts_with("E: INCBIN offset or length -1 is negative"));
+    assert!(errors[2].starts_with("E: Expression `Lbl` is not constant"));
+}
@@ -0,0 +1,136 @@
+mod common;
+
+use common::*;
+use rsgbasm::{Assembler, Diagnostic};
+use std::ops::ControlFlow;
+
+#[test]
+fn string_symbols_are_not_numbers() {
+    let callback = |_: Diagnostic| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble_str("FOO EQUS \"x\"\n").unwrap();
+    assert_eq!(
+        asm.symbol_expr("FOO".to_string())
+            .err()
+            .unwrap()
+            .to_string(),
+        "Symbol \"FOO\" is a string, not a number"
+    );
+}
+
+#[test]
+fn values_are_formatted_like_interpolations() {
+    let callback = |_: Diagnostic| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble_str("N EQU 255\nQ EQU $18000\n").unwrap();
+    let n = asm.symbol_expr("N".to_string()).unwrap();
+    assert_eq!(n.to_hex(4).unwrap(), "00FF");
+    assert_eq!(n.to_bin(10).unwrap(), "0011111111");
+    assert_eq!(n.to_oct(4).unwrap(), "0377");
+    let q = asm.symbol_expr("Q".to_string()).unwrap();
+    assert_eq!(q.to_fixed(3, 16).unwrap(), "1.500");
+    assert_eq!(
+        diagnostics(
+            "N EQU 255\nQ EQU $18000\nSECTION \"a\", ROM0\nwarn \"{f:Q} {x:N} {X:N} {b:N}\"\n"
+        ),
+        ["W: 1.50000 ff FF 11111111"]
+    );
+}
+
+#[test]
+fn at_is_the_current_address() {
+    assert_eq!(
+        bytes("SECTION \"a\", ROMX[$4000]\n db 1, 2\n dw @, @ + 4\n"),
+        [1, 2, 0x02, 0x40, 0x06, 0x40]
+    );
+    assert_eq!(
+        diagnostics("db @\n")[0],
+        "E: PC symbol \"@\" used outside of a section at ???:1:4-1:5"
+    );
+}
+
+#[test]
+fn gfx_constants_are_two_bytes_in_db() {
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\ndb `01230123, 1, (`01230123)\ndw `01230123\n"),
+        [0x55, 0x33, 1, 0x55, 0x33, 0x55, 0x33]
+    );
+    // Arithmetic makes it a plain number again
+    assert_eq!(
+        assemble("SECTION \"a\", ROM0\ndb `01230123 + 0\n"),
+        (
+            vec![0x55],
+            vec!["W: Expression 13141 must be 8-bit".to_string()]
+        )
+    );
+}
+
+#[test]
+fn values_are_range_checked() {
+    let callback = |_: Diagnostic| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble_str("FF EQU $FF\nBIG EQU $100\nNEG EQU -1\n")
+        .unwrap();
+    let value = |name: &str| asm.symbol_expr(name.to_string()).unwrap();
+    assert_eq!(value("FF").as_u8().unwrap(), 0xff);
+    assert_eq!(value("NEG").as_u8().unwrap(), 0xff);
+    assert_eq!(
+        value("BIG").as_u8().unwrap_err().to_string(),
+        "Value 256 doesn't fit in 8 bits"
+    );
+    assert!(value("FF").as_i8().is_err());
+    assert_eq!(value("NEG").as_u16().unwrap(), 0xffff);
+
+    assert_eq!(
+        assemble("SECTION \"a\", ROM0\nld a, -1\nld a, $100\nld bc, $10000\n"),
+        (
+            vec![0x3e, 0xff],
+            vec![
+                "E: Value 256 doesn't fit in 8 bits at ???:3:1-3:11".to_string(),
+                "E: Value 65536 doesn't fit in 16 bits at ???:4:1-4:14".to_string(),
+            ]
+        )
+    );
+}
+
+#[test]
+fn label_differences_in_a_section_are_constant() {
+    let callback = |_: Diagnostic| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble_str("SECTION \"a\", ROM0\nLabel1:\nnop\nnop\nLabel2:\ndb Label2 - Label1, Label1 - Label2\ndw Label2 - Label1\nSECTION \"b\", ROM0\nOther:\ndb Other - Label1\n").unwrap();
+    assert_eq!(*asm.section_bytes("a").unwrap(), [0, 0, 2, 0xfe, 2, 0]);
+    // Sections may be placed anywhere relative to each other, so that is left to the linker
+    let mut obj = Vec::new();
+    asm.write_object(&mut obj).unwrap();
+    let patch = [0, 11, 0, 0, 0, 0x81, 2, 0, 0, 0, 0x81, 0, 0, 0, 0, 0x01]; // Other - Label1
+    assert!(obj.windows(patch.len()).any(|window| window == patch));
+}
+
+#[test]
+fn large_shifts_shift_every_bit_out() {
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\ndb 1 << 40, 1 << 32, 1 << 31 >> 31, -8 >> 40, $80 >> 40\n"),
+        [0, 0, 0xff, 0xff, 0]
+    );
+}
+
+#[test]
+fn negative_shifts_reverse_direction() {
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\ndb 4 << -1, 4 >> -1, 1 << -3, -8 >> -1, 1 << $80000000\n"),
+        [2, 8, 0, 0xf0, 0]
+    );
+}
+
+#[test]
+fn deferred_expressions_are_displayed() {
+    let src = "SECTION \"a\", ROM0\nPa:\nSECTION \"b\", ROM0\nPb:\nPRINTLN (Pa + 2) * (Pb - Pa - 1) << 1\nPRINTLN Pa - (Pb - Pa) || -Pb && ~(Pa & 3)\nPRINTLN -(Pa * 2) + STARTOF(\"b\") % SIZEOF(\"a\")\n";
+    assert_eq!(
+        diagnostics(src),
+        [
+            "E: Expression `(Pa + 2) * (Pb - Pa - 1) << 1` is not constant at ???:5:1-5:38",
+            "E: Expression `Pa - (Pb - Pa) || -Pb && ~(Pa & 3)` is not constant at ???:6:1-6:43",
+            "E: Expression `-(Pa * 2) + STARTOF(\"b\") % 0` is not constant at ???:7:1-7:47",
+        ]
+    );
+}
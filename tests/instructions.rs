@@ -0,0 +1,200 @@
+mod common;
+
+use common::*;
+use rsgbasm::WarningClass;
+
+#[test]
+fn ld_addressing_modes() {
+    let src = "SECTION \"a\", ROM0\nld a, [hl]\nld a, [$1234]\nld [$1234], a\nld [hl], 3\nld [bc], a\nld a, [de]\n";
+    assert_eq!(
+        bytes(src),
+        [0x7e, 0xfa, 0x34, 0x12, 0xea, 0x34, 0x12, 0x36, 3, 0x02, 0x1a]
+    );
+    assert_eq!(
+        diagnostics("SECTION \"a\", ROM0\nld [de], hl\nld [hl], [hl]\n"),
+        [
+            "E: Invalid operands for ld at ???:2:1-2:12",
+            "E: ld [hl], [hl] is not a valid instruction at ???:3:1-3:14",
+        ]
+    );
+}
+
+#[test]
+fn ldh_takes_hram_addresses() {
+    let src = "SECTION \"a\", ROM0\nldh a, [$ff80]\nldh [$ff80], a\nldh [$80], a\nldh a, [$FFFF]\nldh [$ff00 + 4], a\n";
+    assert_eq!(
+        bytes(src),
+        [0xf0, 0x80, 0xe0, 0x80, 0xe0, 0x80, 0xf0, 0xff, 0xe0, 0x04]
+    );
+    assert_eq!(
+        diagnostics("SECTION \"a\", ROM0\nldh a, [$1234]\nldh [$100], a\n"),
+        [
+            "E: Address $1234 is not in HRAM ($FF00-$FFFF) at ???:2:1-2:15",
+            "E: Address $0100 is not in HRAM ($FF00-$FFFF) at ???:3:1-3:14",
+        ]
+    );
+}
+
+#[test]
+fn ldh_to_a_label_is_checked_by_the_linker() {
+    let src =
+        "SECTION \"vars\", HRAM\nhVar: ds 1\nSECTION \"a\", ROM0\nldh a, [hVar]\nldh [hVar + 1], a\n";
+    assert_eq!(bytes(src), [0xf0, 0x00, 0xe0, 0x00]);
+    let obj = rsgbasm::assemble_to_object(src, &Default::default()).unwrap();
+    // Both patches' RPN end with the HRAM check (0x60), after the symbol or the addition
+    assert!(
+        obj.windows(6).any(|w| w[0] == 0x81 && w[5] == 0x60),
+        "{:02x?}",
+        obj
+    );
+    assert!(
+        obj.windows(7).any(|w| w == [0x80, 1, 0, 0, 0, 0x00, 0x60]),
+        "{:02x?}",
+        obj
+    );
+}
+
+#[test]
+fn sp_offsets_are_signed_bytes() {
+    assert_eq!(
+        assemble("SECTION \"a\", ROM0\nadd sp, -1\nld hl, sp+2\nld hl, sp - 128\nadd sp, 200\n"),
+        (
+            vec![0xE8, 0xFF, 0xF8, 0x02, 0xF8, 0x80],
+            vec![
+                "E: Stack pointer offset 200 must be between -128 and 127 at ???:5:1-5:12"
+                    .to_string()
+            ]
+        )
+    );
+}
+
+#[test]
+fn known_jr_offsets_are_checked() {
+    assert_eq!(
+        diagnostics(
+            "SECTION \"a\", ROM0\nFar:\n ds 300\n jr Far\nNear:\n jr Near\n jr Later\nLater:\n"
+        ),
+        ["E: jr offset -302 must be between -128 and 127 at ???:4:2-4:8"]
+    );
+    assert_eq!(
+        assemble("SECTION \"a\", ROM0[$100]\n jr $200\n jr $180\n"),
+        (
+            vec![0x18, 0x00],
+            vec!["E: jr offset 254 must be between -128 and 127 at ???:2:2-2:9".to_string()]
+        )
+    );
+}
+
+#[test]
+fn stop_is_followed_by_a_padding_byte() {
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\nstop\nstop $00\nopt s\nstop\nstop 1\n"),
+        [0x10, 0x00, 0x10, 0x00, 0x10, 0x10, 0x01]
+    );
+}
+
+#[test]
+fn rst_targets_are_checked() {
+    assert_eq!(
+        assemble("SECTION \"a\", ROM0\nrst $38\nrst 0\nrst $08\nrst $05\nrst $40\n"),
+        (
+            vec![0xFF, 0xC7, 0xCF],
+            vec![
+                "E: Invalid rst target 5 at ???:5:1-5:8".to_string(),
+                "E: Invalid rst target 64 at ???:6:1-6:8".to_string(),
+            ]
+        )
+    );
+}
+
+#[test]
+fn ld_between_register_pairs_is_explained() {
+    let errors = diagnostics("SECTION \"a\", ROM0\nld bc, de\nld sp, hl\nld hl, sp\n");
+    assert_eq!(
+        errors,
+        [
+            "E: No such instruction \"ld bc, de\"; use \"ld b, d / ld c, e\" instead at ???:2:1-2:10",
+            "E: No such instruction \"ld hl, sp\" at ???:4:1-4:10",
+        ]
+    );
+}
+
+#[test]
+fn typos_suggest_mnemonics() {
+    assert_eq!(
+        diagnostics("SECTION \"a\", ROM0\nLabel:\n  jmp Label\n  JPP\n  wait\n"),
+        [
+            "E: \"jmp\" is not a macro; did you mean \"jp\"? at ???:3:3-3:12",
+            "E: \"JPP\" is not a macro; did you mean \"jp\"? at ???:4:3-4:6",
+            "E: \"wait\" is not a macro at ???:5:3-5:7",
+        ]
+    );
+    assert_eq!(rsgbasm::suggest_mnemonic("nopp"), Some("nop"));
+    assert_eq!(rsgbasm::suggest_mnemonic("lda"), Some("ld"));
+    assert_eq!(rsgbasm::suggest_mnemonic("foobar"), None);
+}
+
+#[test]
+fn jp_could_be_jr() {
+    let src = "SECTION \"a\", ROM0[$100]\nNear:\nds 3\njp Near\njp nz, Near\nds $100\njp Near\nSECTION \"b\", ROM0\nFloat: jp Float\n";
+    assert_eq!(diagnostics(src), Vec::<String>::new());
+    assert_eq!(
+        diagnostics_with(src, |asm| asm.enable_warning(WarningClass::CouldBeJr)),
+        [
+            "W: jp could be a jr (offset -5)",
+            "W: jp could be a jr (offset -8)"
+        ]
+    );
+}
+
+#[test]
+fn high_c_loads() {
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\nldh [c], a\nld [c], a\nld [$ff00+c], a\nldh [$FF00 + c], a\nldh a, [c]\nld a, [c]\nld a, [$ff00+c]\n"),
+        [0xe2, 0xe2, 0xe2, 0xe2, 0xf2, 0xf2, 0xf2]
+    );
+    let errors =
+        diagnostics("SECTION \"a\", ROM0\nld [c], b\nld a, [$ff01+c]\nld a, [Lbl + c]\nLbl:\n");
+    assert_eq!(errors.len(), 3);
+    assert!(errors[0].starts_with("E: Invalid operands for ld"));
+    assert!(errors[1].starts_with("E: Only $FF00 can be added to c, not `65281`"));
+    assert!(errors[2].starts_with("E: Only $FF00 can be added to c, not `Lbl`"));
+}
+
+#[test]
+fn ldi_ldd_and_hl_inc_dec_are_synonyms() {
+    let forms: [(&[&str], u8); 4] = [
+        (&["ldi a, [hl]", "ld a, [hl+]", "ld a, [hli]"], 0x2a),
+        (&["ldd a, [hl]", "ld a, [hl-]", "ld a, [hld]"], 0x3a),
+        (&["ldi [hl], a", "ld [hl+], a", "ld [hli], a"], 0x22),
+        (&["ldd [hl], a", "ld [hl-], a", "ld [hld], a"], 0x32),
+    ];
+    for (instrs, opcode) in forms {
+        for instr in instrs {
+            assert_eq!(
+                bytes(&format!("SECTION \"a\", ROM0\n{}\n", instr)),
+                [opcode],
+                "{}",
+                instr
+            );
+        }
+    }
+}
+
+#[test]
+fn hl_inc_dec_only_work_with_a() {
+    assert_eq!(diagnostics("SECTION \"a\", ROM0\nld b, [hl+]\n").len(), 1);
+}
+
+#[test]
+fn implicit_accumulator_can_be_linted() {
+    let src = "SECTION \"a\", ROM0\nadd b\nadd a, b\ncp 3\ncp a, 3\nxor a\n";
+    assert_eq!(
+        assemble(src),
+        (vec![0x80, 0x80, 0xfe, 3, 0xfe, 3, 0xaf], vec![])
+    );
+    assert_eq!(
+        diagnostics_with(src, |asm| asm.enable_warning(WarningClass::ImplicitA)),
+        ["W: Accumulator operand `a` is left implicit"; 3]
+    );
+}
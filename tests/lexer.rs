@@ -0,0 +1,189 @@
+mod common;
+
+use common::*;
+use rsgbasm::{Assembler, TokType};
+use std::io::{self, Read};
+use std::ops::ControlFlow;
+
+// Generates a long source on the fly, so that it never exists as a whole
+// `ofs` starts at the end of `line`, which is then repeated `lines_left` times
+struct Repeated {
+    line: &'static [u8],
+    lines_left: usize,
+    ofs: usize,
+}
+
+impl Read for Repeated {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.ofs == self.line.len() {
+            if self.lines_left == 0 {
+                return Ok(0);
+            }
+            self.lines_left -= 1;
+            self.ofs = 0;
+        }
+        let len = buf.len().min(self.line.len() - self.ofs);
+        buf[..len].copy_from_slice(&self.line[self.ofs..][..len]);
+        self.ofs += len;
+        Ok(len)
+    }
+}
+
+#[test]
+fn sources_are_streamed() {
+    let callback = |_| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    let line = b"  db 1 ; long enough for the source to span a couple of megabytes, which nobody wants to load at once\n";
+    let src = b"SECTION \"a\", ROMX\n".chain(Repeated {
+        line,
+        lines_left: 16384,
+        ofs: line.len(),
+    });
+    asm.assemble(src).unwrap();
+    assert_eq!(asm.error_count(), 0);
+    assert_eq!(asm.section_bytes("a").unwrap().len(), 16384);
+}
+
+#[test]
+fn utf8_is_decoded() {
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\ndb \"é→\" ; ☃ in a comment\n"),
+        "é→".as_bytes()
+    );
+    // Invalid UTF-8 is replaced instead of stopping the assembly
+    let callback = |_| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble(&b"SECTION \"a\", ROM0\ndb \"\xFF\"\n"[..])
+        .unwrap();
+    assert_eq!(*asm.section_bytes("a").unwrap(), *"\u{FFFD}".as_bytes());
+}
+
+#[test]
+fn number_literals() {
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\ndb &777 == 511, 1.5 == $18000, $FF == 255\n"),
+        [1, 1, 1]
+    );
+    // Fractional digits past Q16.16's precision are dropped
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\ndl 0.123456789, 0.123456789123456, -1.5\n"),
+        [0x9a, 0x1f, 0, 0, 0x9a, 0x1f, 0, 0, 0, 0x80, 0xfe, 0xff]
+    );
+    assert_eq!(
+        diagnostics("SECTION \"a\", ROM0\ndl 40000.5\n"),
+        ["W: Integer part 40000 of fixed-point constant must be 16-bit"]
+    );
+    assert_eq!(
+        diagnostics("SECTION \"a\", ROM0\ndl 1.\n")[0],
+        "E: Invalid fixed-point constant, no digits after '.' at ???:2:4-2:6"
+    );
+}
+
+#[test]
+fn digit_separators() {
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\ndb $DE_AD == $DEAD, 1_000 == 1000, %1010_1010 == $AA, &7_7 == 63, 1_0.5 == 10.5\n"),
+        [1, 1, 1, 1, 1]
+    );
+    assert_eq!(
+        diagnostics("SECTION \"a\", ROM0\ndb $_FF\ndb $FF_\ndb 1__0\ndb %1_\n"),
+        [
+            "E: Digit separators must be placed between digits at ???:2:4-2:6",
+            "E: Digit separators must be placed between digits at ???:3:4-3:8",
+            "E: Digit separators must be placed between digits at ???:4:4-4:8",
+            "E: Digit separators must be placed between digits at ???:5:4-5:7",
+        ]
+    );
+}
+
+#[test]
+fn c_prefixes_are_opt_in() {
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\nopt c\ndb 0xFF == $FF, 0b1010, 0XF_F, %11\n"),
+        [1, 0x0a, 0xff, 3]
+    );
+    assert_eq!(
+        diagnostics("SECTION \"a\", ROM0\nopt c\ndb 0x\n")[0],
+        "E: Invalid constant, no digits after '0x' at ???:3:4-3:6"
+    );
+
+    let callback = |_| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    let tokens = asm.dump_tokens("db 0xFF\n");
+    assert!(matches!(tokens[1].1, TokType::Num(0)));
+    assert!(matches!(&tokens[2].1, TokType::Ident(name) if name == "xFF"));
+}
+
+#[test]
+fn tokens_can_be_dumped() {
+    let callback = |_| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    let tokens: Vec<_> = asm
+        .dump_tokens("Main:\n ld a, [$FF00 + 3] ; hi\n")
+        .iter()
+        .map(|(begin, tok, end)| {
+            format!(
+                "{}:{} {} {}:{}",
+                begin.line_no(),
+                begin.col_no(),
+                tok,
+                end.line_no(),
+                end.col_no()
+            )
+        })
+        .collect();
+    assert_eq!(
+        tokens,
+        [
+            "1:1 label 1:5",
+            "1:5 : 1:6",
+            "1:6 \n 2:1",
+            "2:2 ld 2:4",
+            "2:5 a 2:6",
+            "2:6 , 2:7",
+            "2:8 [ 2:9",
+            "2:9 number 2:14",
+            "2:15 + 2:16",
+            "2:17 number 2:18",
+            "2:18 ] 2:19",
+            "2:24 \n 3:1",
+        ]
+    );
+}
+
+#[test]
+fn spans_cover_whole_tokens() {
+    let callback = |_| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    let (begin, _, end) = asm.dump_tokens(" db 12345\n")[1].clone();
+    assert_eq!((begin.col_no(), end.col_no()), (5, 10));
+
+    let errors = diagnostics("SECTION \"a\", ROM0\n db 1 12345\n");
+    assert!(errors[0].starts_with("E: Unexpected number at ???:2:7-2:12;"));
+}
+
+#[test]
+fn final_newline_is_optional() {
+    let expected = [0x00, 0x3e, 0x03];
+    assert_eq!(bytes("SECTION \"a\", ROM0\nnop\nld a, 3\n"), expected);
+    assert_eq!(bytes("SECTION \"a\", ROM0\nnop\nld a, 3"), expected);
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\nnop\nld a, 3 ; comment"),
+        expected
+    );
+    assert_eq!(diagnostics("OPT s"), Vec::<String>::new());
+    assert_eq!(diagnostics(""), Vec::<String>::new());
+}
+
+#[test]
+fn lexer_errors_have_locations() {
+    let errors = diagnostics("SECTION \"a\", ROM0\n  db 1, \"abc\n  db \"\\q\"\n  db 'ab\n");
+    assert_eq!(
+        errors[..3],
+        [
+            "E: Unterminated string at ???:2:9-2:13",
+            "E: Illegal character escape 'q' at ???:3:6-3:9",
+            "E: Unterminated character constant at ???:4:6-4:9",
+        ]
+    );
+}
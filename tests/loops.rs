@@ -0,0 +1,115 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn rept_repeats_its_body() {
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\nREPT 3\ndb 1\nENDR\nREPT 0\ndb 2\nENDR\n"),
+        [1, 1, 1]
+    );
+    assert_eq!(
+        diagnostics("REPT -1\ndb 8\nENDR\n"),
+        ["E: REPT count -1 is negative at ???:1:1-1:8"]
+    );
+}
+
+#[test]
+fn for_sets_its_variable() {
+    assert_eq!(
+        bytes(
+            "SECTION \"a\", ROM0\nFOR x, 3\ndb x\nENDR\ndb x\nFOR x, 10, 4, -3\ndb x\nENDR\ndb x\n"
+        ),
+        [0, 1, 2, 3, 10, 7, 4]
+    );
+    assert_eq!(
+        diagnostics("FOR x, 0, 3, 0\ndb 9\nENDR\n"),
+        ["E: FOR cannot have a step value of 0 at ???:1:1-1:15"]
+    );
+    assert_eq!(
+        diagnostics("x EQU 1\nFOR x, 3\nENDR\n"),
+        ["E: Cannot redefine EQU symbol \"x\" as SET at ???:2:1-2:9"]
+    );
+}
+
+#[test]
+fn loops_nest() {
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\nFOR x, 3\nREPT 2\ndb x\nENDR\nENDR\n"),
+        [0, 0, 1, 1, 2, 2]
+    );
+}
+
+#[test]
+fn break_inside_if_leaves_the_loop() {
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\nFOR i, 0, 10\n  IF i == 2\n    BREAK\n  ENDC\n  db i\nENDR\ndb $FF\n"),
+        [0, 1, 0xFF]
+    );
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\nx = 0\nWHILE x < 3\ndb x\nx = x + 1\nIF x == 2\nBREAK\nENDC\nENDR\n"),
+        [0, 1]
+    );
+}
+
+#[test]
+fn break_only_leaves_the_innermost_loop() {
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\nFOR i, 3\nFOR j, 3\nIF j > i\nBREAK\nENDC\ndb i * 16 + j\nENDR\nENDR\n"),
+        [0x00, 0x10, 0x11, 0x20, 0x21, 0x22]
+    );
+    assert_eq!(
+        diagnostics("BREAK\n"),
+        ["E: BREAK can only be used inside a loop"]
+    );
+}
+
+#[test]
+fn each_iteration_has_its_own_locals() {
+    assert_eq!(
+        labels("SECTION \"a\", ROM0[0]\nMain:\nREPT 2\n.loop: dec a\njr nz, .loop\nENDR\n"),
+        [
+            "00:0000 Main",
+            "00:0000 Main.loop_u1",
+            "00:0003 Main.loop_u2"
+        ]
+    );
+    assert_eq!(
+        labels("SECTION \"a\", ROM0[0]\nMain:\nx = 0\nWHILE x < 2\n.w: db x\nx = x + 1\nENDR\n"),
+        ["00:0000 Main", "00:0000 Main.w_u1", "00:0001 Main.w_u2"]
+    );
+}
+
+#[test]
+fn while_reevaluates_its_condition() {
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\ni = 0\n WHILE i < 3\n db i\ni = i + 1\n ENDR\n while 0\n db 1 / 0\n endr\n"),
+        [0, 1, 2]
+    );
+}
+
+#[test]
+fn while_loops_are_capped() {
+    assert_eq!(
+        assemble("SECTION \"a\", ROM0\nk = 0\n while 1\nk = k + 1\n endr\n db k == 65536\n"),
+        (
+            vec![1],
+            vec!["E: WHILE loop still running after 65536 iterations".to_string()]
+        )
+    );
+}
+
+#[test]
+fn equs_interacts_with_loops() {
+    // `BREAK` may come from an `EQUS`, and `FOR`'s variable is never expanded
+    assert_eq!(
+        bytes(
+            "SECTION \"a\", ROM0\nstop_here EQUS \"BREAK\\ndb 3\"\nREPT 3\ndb 1\n stop_here\ndb 2\nENDR\n"
+        ),
+        [1]
+    );
+    assert_eq!(
+        diagnostics("x EQUS \"y\"\nFOR x, 3\nENDR\n"),
+        ["E: Redefined symbol at ???:2:1-2:9"]
+    );
+}
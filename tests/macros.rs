@@ -0,0 +1,45 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn each_invocation_has_its_own_locals() {
+    let src =
+        "SECTION \"a\", ROM0[0]\nmac: MACRO\n.loop: dec a\njr nz, .loop\nENDM\nMain:\n mac\n mac\n";
+    assert_eq!(diagnostics(src), Vec::<String>::new());
+    assert_eq!(
+        labels(src),
+        [
+            "00:0000 Main",
+            "00:0000 Main.loop_u1",
+            "00:0003 Main.loop_u2"
+        ]
+    );
+}
+
+#[test]
+fn recursion_is_limited() {
+    assert_eq!(
+        diagnostics_with("mac: MACRO\n mac\nENDM\n mac\n", |asm| asm
+            .set_max_recursion_depth(8)),
+        ["E: Recursion limit (8) exceeded at ???:1:2-1:5"]
+    );
+    let errors = diagnostics("X EQUS \"X\"\nSECTION \"a\", ROM0\ndb X\n");
+    assert_eq!(errors[0], "E: Recursion limit (64) exceeded at ???:1:1-3:5");
+}
+
+#[test]
+fn narg_counts_the_arguments() {
+    assert_eq!(
+        bytes("_NARG EQU 42\nSECTION \"a\", ROM0\nmac: MACRO\n db _NARG\n shift\n db _NARG, \\1\nENDM\n mac 1, 2, 3\n db _NARG\n"),
+        [3, 2, 2, 42]
+    );
+}
+
+#[test]
+fn arguments_can_contain_commas() {
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\nnarg: MACRO\n db _NARG\nENDM\nfirst: MACRO\n db \\1\nENDM\n narg 1\\, 2\n narg \"a,b\", 3\n narg (1, 2) + 1\n first 1\\, 2\n first \"a,b\", 3\n"),
+        [1, 2, 1, 1, 2, b'a', b',', b'b']
+    );
+}
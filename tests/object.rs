@@ -0,0 +1,151 @@
+use rsgbasm::{Assembler, AssemblerOptions, Diagnostic};
+use std::ops::ControlFlow;
+
+fn object(src: &str) -> Vec<u8> {
+    let callback = |_| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble_str(src).unwrap();
+    let mut out = Vec::new();
+    asm.write_object(&mut out).unwrap();
+    out
+}
+
+fn long(val: i32) -> [u8; 4] {
+    val.to_le_bytes()
+}
+
+#[test]
+fn minimal_object_file() {
+    let obj = object("SECTION \"a\", ROM0\nStart::\n db 1\n dw Far\n");
+
+    let mut expected = Vec::new();
+    expected.extend_from_slice(b"RGB9");
+    expected.extend_from_slice(&long(9)); // Revision
+    expected.extend_from_slice(&long(2)); // Symbols
+    expected.extend_from_slice(&long(1)); // Sections
+
+    // File stack
+    expected.extend_from_slice(&long(1));
+    expected.extend_from_slice(&long(-1));
+    expected.extend_from_slice(&long(0));
+    expected.push(1);
+    expected.extend_from_slice(b"???\0");
+
+    // Symbols
+    expected.extend_from_slice(b"Start\0");
+    expected.push(2); // Exported
+    expected.extend_from_slice(&long(0)); // Node
+    expected.extend_from_slice(&long(2)); // Line
+    expected.extend_from_slice(&long(0)); // Section
+    expected.extend_from_slice(&long(0)); // Value
+    expected.extend_from_slice(b"Far\0");
+    expected.push(1); // Imported
+
+    // Sections
+    expected.extend_from_slice(b"a\0");
+    expected.extend_from_slice(&long(3)); // Size
+    expected.push(3); // ROM0
+    expected.extend_from_slice(&long(-1)); // Address
+    expected.extend_from_slice(&long(0)); // Bank
+    expected.push(0); // Alignment
+    expected.extend_from_slice(&long(0));
+    expected.extend_from_slice(&[1, 0, 0]);
+    expected.extend_from_slice(&long(1)); // Patches
+    expected.extend_from_slice(&long(0)); // Node
+    expected.extend_from_slice(&long(4)); // Line
+    expected.extend_from_slice(&long(1)); // Offset
+    expected.extend_from_slice(&long(0)); // PC section
+    expected.extend_from_slice(&long(1)); // PC offset
+    expected.push(1); // Word
+    expected.extend_from_slice(&long(5));
+    expected.extend_from_slice(&[0x81, 1, 0, 0, 0]); // Symbol #1
+
+    // Assertions
+    expected.extend_from_slice(&long(0));
+
+    assert_eq!(obj, expected);
+}
+
+#[test]
+fn unresolved_assertions_are_left_to_the_linker() {
+    let obj = object("SECTION \"a\", ROM0\n nop\n ASSERT LABEL < $8000, \"too far\"\n assert 1\n");
+
+    let mut expected = Vec::new();
+    expected.extend_from_slice(&long(1)); // Assertions
+    expected.extend_from_slice(&long(0)); // Node
+    expected.extend_from_slice(&long(3)); // Line
+    expected.extend_from_slice(&long(1)); // Offset
+    expected.extend_from_slice(&long(0)); // PC section
+    expected.extend_from_slice(&long(1)); // PC offset
+    expected.push(1); // Error
+    expected.extend_from_slice(&long(11));
+    expected.extend_from_slice(&[0x81, 0, 0, 0, 0]); // Symbol #0
+    expected.extend_from_slice(&[0x80, 0x00, 0x80, 0, 0]); // $8000
+    expected.push(0x33); // <
+    expected.extend_from_slice(b"too far\0");
+
+    assert!(obj.ends_with(&expected));
+}
+
+#[test]
+fn objects_are_not_written_after_errors() {
+    let callback = |_| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble_str("SECTION \"a\", ROM0\nld bc, de\n")
+        .unwrap();
+    assert!(asm.write_object(Vec::new()).is_err());
+}
+
+#[test]
+fn partial_objects_are_flagged() {
+    let callback = |_| ControlFlow::Continue(());
+    let mut asm = Assembler::new(&callback);
+    asm.allow_partial_output(true);
+    asm.assemble_str("SECTION \"good\", ROM0\n db 1, 2\n db 1/0\n")
+        .unwrap();
+    let mut obj = Vec::new();
+    asm.write_object(&mut obj).unwrap();
+    assert_eq!(obj[4..8], long(9 | 0x100)); // Revision, with the "had errors" flag
+    assert!(obj.windows(5).any(|name| name == b"good\0"));
+}
+
+#[test]
+fn patches_are_simplified() {
+    let has_patch = |src: &str, patch: &[u8]| {
+        object(src)
+            .windows(patch.len())
+            .any(|window| window == patch)
+    };
+    // Symbol #0 is `Ext`; a symbol plus an offset folds the offset into one addition
+    assert!(has_patch(
+        "SECTION \"a\", ROM0\ndw Ext\n",
+        &[1, 5, 0, 0, 0, 0x81, 0, 0, 0, 0]
+    ));
+    assert!(has_patch(
+        "SECTION \"a\", ROM0\ndw (Ext + 2) + 3\n",
+        &[1, 11, 0, 0, 0, 0x81, 0, 0, 0, 0, 0x80, 5, 0, 0, 0, 0x00]
+    ));
+    assert!(has_patch(
+        "SECTION \"a\", ROM0\ndw 10 + Ext - 4\n",
+        &[1, 11, 0, 0, 0, 0x81, 0, 0, 0, 0, 0x80, 6, 0, 0, 0, 0x00]
+    ));
+    // Anything else is written as is
+    assert!(has_patch(
+        "SECTION \"a\", ROM0\ndw Ext * Ext2\n",
+        &[1, 11, 0, 0, 0, 0x81, 0, 0, 0, 0, 0x81, 1, 0, 0, 0, 0x02]
+    ));
+}
+
+#[test]
+fn objects_can_be_assembled_in_one_call() {
+    let opts = AssemblerOptions::default();
+    let obj = rsgbasm::assemble_to_object("SECTION \"a\", ROM0\nld a, 3\n", &opts).unwrap();
+    assert!(obj.starts_with(b"RGB9"));
+
+    let diags = rsgbasm::assemble_to_object("SECTION \"a\", ROM0\nld bc, de\nwarn \"hi\"\n", &opts)
+        .unwrap_err();
+    assert!(matches!(
+        diags[..],
+        [Diagnostic::Error(_), Diagnostic::Warning(_)]
+    ));
+}
@@ -0,0 +1,84 @@
+mod common;
+
+use common::*;
+use rsgbasm::{Assembler, AssemblerOptions};
+use std::ops::ControlFlow;
+
+#[test]
+fn pusho_and_popo_restore_options() {
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\nds 2\nPUSHO\nOPT z$FF\nds 2\nPOPO\nds 2\n"),
+        [0, 0, 0xFF, 0xFF, 0, 0]
+    );
+    assert_eq!(
+        diagnostics("POPO\n"),
+        ["E: No entries in the option stack at ???:1:1-1:5"]
+    );
+}
+
+#[test]
+fn opt_does_not_outlive_an_assembly() {
+    let callback = |_| ControlFlow::Continue(());
+    let mut asm = Assembler::new(&callback);
+    asm.set_options(AssemblerOptions {
+        fill_byte: 0xAA,
+        ..Default::default()
+    });
+    for _ in 0..2 {
+        asm.assemble_str("SECTION \"a\", ROM0\nds 2\nOPT z$FF\nds 1\n")
+            .unwrap();
+        assert_eq!(*asm.section_bytes("a").unwrap(), [0xAA, 0xAA, 0xFF]);
+    }
+}
+
+#[test]
+fn opt_g_remaps_gfx_digits() {
+    assert_eq!(bytes("SECTION \"a\", ROM0\ndw `01230123\n"), [0x55, 0x33]);
+    assert_eq!(
+        assemble("SECTION \"a\", ROM0\nOPT g.xXO\ndw `.xXO.xXO, `OOOOOOOO\nOPT g..XO\nOPT gabc\n"),
+        (
+            vec![0x55, 0x33, 0xFF, 0xFF],
+            vec![
+                "E: Invalid option \"g..XO\" at ???:4:1-4:10".to_string(),
+                "E: Invalid option \"gabc\" at ???:5:1-5:9".to_string(),
+            ]
+        )
+    );
+}
+
+#[test]
+fn opt_b_remaps_binary_digits() {
+    assert_eq!(
+        assemble("SECTION \"a\", ROM0\nOPT b.X\ndb %X.X.\nOPT bXX\nOPT b.\ndb 7 % 4\n"),
+        (
+            vec![0b1010, 3],
+            vec![
+                "E: Invalid option \"bXX\" at ???:4:1-4:8".to_string(),
+                "E: Invalid option \"b.\" at ???:5:1-5:7".to_string(),
+            ]
+        )
+    );
+}
+
+#[test]
+fn pusho_and_popo_restore_digits() {
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\nopt g.xXO, b.X\npusho\nopt gabcd, bAB\ndw `abcd, %AB\npopo\ndw `.xXO, %X.\n"),
+        [5, 3, 1, 0, 5, 3, 2, 0]
+    );
+}
+
+#[test]
+fn opt_q_sets_the_fixed_point_precision() {
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\ndl 1.5\nopt Q.8\ndl 1.5, 0.25\n"),
+        [0x00, 0x80, 0x01, 0x00, 0x80, 0x01, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00]
+    );
+    assert_eq!(
+        diagnostics("opt Q32\nopt Q0\n"),
+        [
+            "E: Invalid option \"Q32\" at ???:1:1-1:8",
+            "E: Invalid option \"Q0\" at ???:2:1-2:7",
+        ]
+    );
+}
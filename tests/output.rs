@@ -0,0 +1,107 @@
+mod common;
+
+use common::*;
+use rsgbasm::{Assembler, Diagnostic};
+use std::cell::RefCell;
+use std::ops::ControlFlow;
+
+#[test]
+fn sym_file_lists_placed_labels() {
+    // Floating labels have no address yet, and constants aren't labels
+    assert_eq!(
+        labels("SECTION \"hram\", HRAM[$FF80]\nhVar:\nSECTION \"code\", ROM0[$150]\nMain::\n nop\nZed:\n halt\nSECTION \"f\", ROM0\nFloat:\nCONST EQU 3\n"),
+        ["00:0150 Main", "00:0151 Zed", "00:ff80 hVar"]
+    );
+}
+
+#[test]
+fn listing_shows_emitted_bytes() {
+    let listing = SharedBuf::default();
+    let callback = |_| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback).with_listing(listing.clone());
+    asm.assemble_str("SECTION \"code\", ROM0[$150]\nMain:\n ld a, 42 ; load\n db \"Hello, world!\"\n\n jp Main\n")
+        .unwrap();
+    drop(asm);
+    assert_eq!(
+        listing.text(),
+        concat!(
+            "                   SECTION \"code\", ROM0[$150]\n",
+            "                   Main:\n",
+            "0150  3e 2a         ld a, 42 ; load\n",
+            "0152  48 65 6c 6c   db \"Hello, world!\"\n",
+            "0156  6f 2c 20 77\n",
+            "015a  6f 72 6c 64\n",
+            "015e  21\n",
+            "\n",
+            "015f  c3 50 01      jp Main\n",
+        )
+    );
+}
+
+#[test]
+fn rom_images_are_padded() {
+    let callback = |_| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble_str(
+        "OPT p$FF\nSECTION \"a\", ROM0[$100]\n db 1, 2\nSECTION \"b\", ROM0[0]\n db 3\n",
+    )
+    .unwrap();
+    let mut rom = Vec::new();
+    asm.write_rom(&mut rom).unwrap();
+    assert_eq!(rom.len(), 0x4000);
+    assert_eq!(rom[..2], [3, 0xFF]);
+    assert_eq!(rom[0xFF..0x103], [0xFF, 1, 2, 0xFF]);
+    assert_eq!(rom[0x3FFF], 0xFF);
+
+    asm.assemble_str("SECTION \"a\", ROM0\n db 1\n").unwrap();
+    assert_eq!(
+        asm.write_rom(Vec::new()).unwrap_err().to_string(),
+        "Section \"a\" can only be placed by linking"
+    );
+}
+
+#[test]
+fn outputs_are_reproducible() {
+    let build = || {
+        let callback = |_| ControlFlow::Continue(());
+        let asm = Assembler::new(&callback);
+        asm.assemble_str(
+            "SECTION \"a\", ROM0[0]\nZed:\nAlpha::\nMid:\n dw Ext1, Ext2, Ext3\nB_:\nC_:\n",
+        )
+        .unwrap();
+        let (mut sym, mut obj) = (Vec::new(), Vec::new());
+        asm.write_sym_file(&mut sym).unwrap();
+        asm.write_object(&mut obj).unwrap();
+        (sym, obj)
+    };
+    let first = build();
+    for _ in 0..10 {
+        assert_eq!(build(), first);
+    }
+}
+
+#[test]
+fn legacy_print_directives() {
+    let output = SharedBuf::default();
+    let warnings = RefCell::new(Vec::new());
+    let callback = |diag| {
+        if let Diagnostic::Warning(warning) = diag {
+            warnings.borrow_mut().push(warning.to_string());
+        }
+        ControlFlow::Continue(())
+    };
+    let asm = Assembler::new(&callback).with_print_output(output.clone());
+    asm.assemble_str("PRINTV $FF\nPRINTI -3\nPRINTT \"hi\"\nPRINTF 1.5\nPRINTLN\n")
+        .unwrap();
+    drop(asm);
+    assert_eq!(output.text(), "255-3hi1.50000\n");
+    assert_eq!(
+        *warnings.borrow(),
+        [
+            "`PRINTV` is deprecated; use `PRINTLN` instead",
+            "`PRINTI` is deprecated; use `PRINTLN` instead",
+            "`PRINTT` is deprecated; use `PRINTLN` instead",
+            "`PRINTF` is deprecated; use `PRINTLN` instead",
+        ]
+    );
+}
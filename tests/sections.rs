@@ -0,0 +1,200 @@
+mod common;
+
+use common::*;
+use rsgbasm::{Assembler, Diagnostic, SectionInfo, SectionType};
+use std::ops::ControlFlow;
+
+#[test]
+fn layout_problems_are_all_reported() {
+    let callback = |_: Diagnostic| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble_str(
+        "SECTION \"a\", ROM0[$100]\n ds 8\nSECTION \"b\", ROM0[$104]\n db 1\nSECTION \"c\", ROM0[$3FFF]\n dw 1\nSECTION \"d\", ROM0[$108]\n db 1\n",
+    )
+    .unwrap();
+    let errors: Vec<_> = asm
+        .validate_layout()
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    assert_eq!(
+        errors,
+        [
+            "Section \"c\" doesn't fit in its memory region",
+            "Sections \"a\" and \"b\" overlap",
+        ]
+    );
+}
+
+#[test]
+fn load_blocks_label_ram_addresses() {
+    let src = "SECTION \"a\", ROM0[$100]\nStart:\n db 1\n LOAD \"ram\", WRAM0[$C000]\nRamCode:\n nop\n nop\nRamEnd:\n ENDL\nAfter:\n db 2\n";
+    assert_eq!(bytes(src), [1, 0, 0, 2]);
+    assert_eq!(
+        labels(src),
+        [
+            "00:0100 Start",
+            "00:0103 After",
+            "00:c000 RamCode",
+            "00:c002 RamEnd"
+        ]
+    );
+    assert_eq!(
+        diagnostics("SECTION \"a\", ROM0\n ENDL\n"),
+        ["E: Found ENDL outside of a LOAD block at ???:2:2-2:6"]
+    );
+}
+
+#[test]
+fn fragments_are_concatenated() {
+    assert_eq!(
+        assemble("SECTION FRAGMENT \"a\", ROM0\n db 1, 2\nSECTION \"b\", ROM0\n db 9\nSECTION FRAGMENT \"a\", ROM0\n db 3\nSECTION \"a\", ROM0\n"),
+        (
+            vec![1, 2, 3],
+            vec!["E: Section \"a\" already exists at ???:7:1-7:18".to_string()]
+        )
+    );
+}
+
+#[test]
+fn sizeof_and_startof_query_sections() {
+    assert_eq!(
+        bytes("SECTION \"Name\", ROM0[$150]\n db 1, 2, 3, 4, 5\nSECTION \"a\", ROMX\n db SIZEOF(\"Name\") == 5, STARTOF(\"Name\") == $150\n"),
+        [1, 1]
+    );
+}
+
+#[test]
+fn fills_are_appended_to_the_section() {
+    let callback = |_: Diagnostic| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble_str("SECTION \"a\", ROM0\n db 1\n").unwrap();
+    asm.emit_fill(0x90, 3).unwrap();
+    assert_eq!(*asm.section_bytes("a").unwrap(), [1, 0x90, 0x90, 0x90]);
+}
+
+#[test]
+fn section_attributes_are_checked() {
+    assert_eq!(
+        diagnostics("SECTION \"X\", ROMX, BANK[3]\nSECTION \"Y\", ROMX, BANK[600]\nSECTION \"Z\", ROM0, BANK[1]\nSECTION \"A\", ROM0[$100], ALIGN[8, 1]\nSECTION \"B\", ROM0, ALIGN[17]\n"),
+        [
+            "E: Bank 600 is out of range for ROMX sections at ???:2:1-2:29",
+            "E: BANK is only allowed for ROMX, VRAM, SRAM and WRAMX sections, not ROM0 at ???:3:1-3:27",
+            "E: Section address doesn't match its alignment at ???:4:1-4:37",
+            "E: Alignment 17 must be between 0 and 16 at ???:5:1-5:29",
+        ]
+    );
+}
+
+#[test]
+fn pops_restores_the_pc() {
+    let callback = |_: Diagnostic| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble_str("SECTION \"a\", ROM0[$100]\n db 1, 2\n pushs\nSECTION \"b\", ROM0[$200]\n db 3\n dw @\n pops\n dw @\n")
+        .unwrap();
+    assert_eq!(asm.error_count(), 0);
+    assert_eq!(*asm.section_bytes("a").unwrap(), [1, 2, 0x02, 0x01]);
+    assert_eq!(*asm.section_bytes("b").unwrap(), [3, 0x01, 0x02]);
+}
+
+#[test]
+fn section_types_round_trip() {
+    for name in [
+        "ROM0", "ROMX", "VRAM", "SRAM", "WRAM0", "WRAMX", "OAM", "HRAM",
+    ] {
+        let sect_type: SectionType = name.parse().unwrap();
+        assert_eq!(sect_type.to_string(), name);
+        assert_eq!(
+            name.to_lowercase().parse::<SectionType>().unwrap(),
+            sect_type
+        );
+    }
+    assert!("ROM1".parse::<SectionType>().is_err());
+
+    assert_eq!(SectionType::Rom0.address_range(), 0x0000..=0x3fff);
+    assert_eq!(SectionType::Romx.address_range(), 0x4000..=0x7fff);
+    assert_eq!(SectionType::Oam.address_range(), 0xfe00..=0xfe9f);
+    assert_eq!(SectionType::Hram.address_range(), 0xff80..=0xfffe);
+    assert!(SectionType::Romx.is_rom() && SectionType::Romx.is_banked());
+    assert!(!SectionType::Hram.is_rom() && !SectionType::Hram.is_banked());
+    assert_eq!(SectionType::Wramx.default_bank(), 1);
+    assert_eq!(SectionType::Vram.default_bank(), 0);
+}
+
+#[test]
+fn section_bytes_are_exposed() {
+    let callback = |_: Diagnostic| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble_str("SECTION \"code\", ROM0\n nop\n ld a, 1\n")
+        .unwrap();
+    assert_eq!(*asm.section_bytes("code").unwrap(), [0x00, 0x3e, 0x01]);
+    assert!(asm.section_bytes("nope").is_none());
+}
+
+#[test]
+fn ds_only_fills_rom_sections() {
+    let callback = |_: Diagnostic| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble_str(
+        "SECTION \"ram\", WRAM0[$C000]\n ds 4\nVar:\nSECTION \"rom\", ROM0\n opt z$AA\n ds 4\n",
+    )
+    .unwrap();
+    assert!(asm.section_bytes("ram").unwrap().is_empty());
+    assert_eq!(*asm.section_bytes("rom").unwrap(), [0xaa; 4]);
+    drop(asm);
+    assert_eq!(
+        labels("SECTION \"ram\", WRAM0[$C000]\n ds 4\nVar:\n"),
+        ["00:c004 Var"]
+    );
+}
+
+#[test]
+fn code_before_a_section_is_kept() {
+    let callback = |_: Diagnostic| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble_str("Start:\nnop\njr Start\nSECTION \"a\", ROM0\n")
+        .unwrap();
+    assert_eq!(
+        *asm.section_bytes("(implicit)").unwrap(),
+        [0x00, 0x18, 0x00]
+    );
+    assert_eq!(
+        diagnostics("nop\n"),
+        ["W: Code before the first SECTION is placed in an implicit ROM0 section"]
+    );
+}
+
+#[test]
+fn section_layout_can_be_queried() {
+    let callback = |_: Diagnostic| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble_str("SECTION \"code\", ROM0[$150]\nnop\nnop\nSECTION \"far\", ROMX, BANK[3]\ndw 0, 0\nSECTION \"vars\", WRAM0\nds 5\n").unwrap();
+    let infos: Vec<_> = asm.sections_iter().collect();
+    assert_eq!(
+        infos,
+        [
+            SectionInfo {
+                name: "code".into(),
+                sect_type: SectionType::Rom0,
+                bank: Some(0),
+                start: Some(0x150),
+                len: 2
+            },
+            SectionInfo {
+                name: "far".into(),
+                sect_type: SectionType::Romx,
+                bank: Some(3),
+                start: None,
+                len: 4
+            },
+            SectionInfo {
+                name: "vars".into(),
+                sect_type: SectionType::Wram0,
+                bank: Some(0),
+                start: None,
+                len: 5
+            },
+        ]
+    );
+    assert!(infos[0].is_fixed() && !infos[1].is_fixed());
+}
@@ -0,0 +1,29 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn char_constants_go_through_the_charmap() {
+    assert_eq!(bytes("SECTION \"a\", ROM0\ndb 'A'\n"), [0x41]);
+    // Multi-byte mappings are big-endian, like RGBDS
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\nCHARMAP \"AB\", 1, 2\ndw 'AB'\n"),
+        [2, 1]
+    );
+    assert_eq!(
+        diagnostics("SECTION \"a\", ROM0\ndb 'AB'\n")[0],
+        "E: Character constant 'AB' is more than one character at ???:2:4-2:8"
+    );
+}
+
+#[test]
+fn escape_sequences() {
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\ndb \"\\t\\r\\n\\0\\\\\\\"\\{\\}\", '\\t'\n"),
+        b"\t\r\n\0\\\"{}\t"
+    );
+    assert_eq!(
+        diagnostics("SECTION \"a\", ROM0\ndb \"\\q\"\n"),
+        ["E: Illegal character escape 'q' at ???:2:4-2:7"]
+    );
+}
@@ -0,0 +1,347 @@
+mod common;
+
+use common::*;
+use rsgbasm::{Assembler, AssemblerOptions, Diagnostic, Symbol, WarningClass};
+use std::ops::ControlFlow;
+
+#[test]
+fn cli_symbols_are_predefined() {
+    let configure = |asm: &mut Assembler| {
+        for spec in ["DEBUG", "VERSION=3", "HEX=$10", "NAME=hello"] {
+            asm.define_cli_symbol(spec).unwrap();
+        }
+        assert!(asm.define_cli_symbol("=3").is_err());
+    };
+    assert_eq!(
+        assemble_with(
+            "SECTION \"a\", ROM0\ndb DEF(DEBUG), DEBUG, VERSION, HEX, \"{NAME}\"\n",
+            configure
+        ),
+        (b"\x01\x01\x03\x10hello".to_vec(), vec![])
+    );
+}
+
+#[test]
+fn cli_symbol_values_are_lexed_like_numbers() {
+    let configure = |asm: &mut Assembler| {
+        asm.set_options(AssemblerOptions {
+            c_prefixes: true,
+            ..Default::default()
+        });
+        for spec in [
+            "SEP=1_000",
+            "NEG=-1",
+            "FIX=1.5",
+            "CHEX=0x10",
+            "PLUS=+5",
+            "HEX=$+5",
+            "NUM=1a",
+        ] {
+            asm.define_cli_symbol(spec).unwrap();
+        }
+    };
+    assert_eq!(
+        assemble_with(
+            "SECTION \"a\", ROM0\ndw SEP, NEG, FIX >> 8\ndb CHEX, \"{PLUS}{HEX}{NUM}\"\n",
+            configure
+        ),
+        (b"\xe8\x03\xff\xff\x80\x01\x10+5$+51a".to_vec(), vec![])
+    );
+}
+
+#[test]
+fn recursion_is_limited() {
+    let src = format!(
+        "SECTION \"a\", ROM0\ndb \"{}X{}\"\n",
+        "{".repeat(10),
+        "}".repeat(10)
+    );
+    assert_eq!(
+        diagnostics_with(&src, |asm| asm.set_max_recursion_depth(8)),
+        ["E: Recursion limit (8) exceeded at ???:2:4-2:26"]
+    );
+    assert_eq!(
+        diagnostics(&src),
+        ["E: Interpolated symbol \"X\" does not exist at ???:2:4-2:17"]
+    );
+}
+
+#[test]
+fn assemble_str_defines_symbols() {
+    assert_eq!(
+        labels("SECTION \"a\", ROM0[$150]\nStart:\n nop\nEnd:\n"),
+        ["00:0150 Start", "00:0151 End"]
+    );
+}
+
+#[test]
+fn locals_resolve_through_the_current_scope() {
+    let callback = |_: Diagnostic| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble_str("SECTION \"a\", ROM0\nMain:\n.loop:\n")
+        .unwrap();
+    let sym = asm.resolve_symbol(".loop").unwrap().unwrap();
+    assert_eq!(sym.get_name().as_str(), "Main.loop");
+    drop(sym);
+
+    let asm = Assembler::new(&callback);
+    asm.assemble_str("SECTION \"a\", ROM0\n").unwrap();
+    assert_eq!(
+        asm.resolve_symbol(".loop").err().unwrap().to_string(),
+        "Local symbol \".loop\" in main scope"
+    );
+}
+
+#[test]
+fn symbols_are_visited_in_order() {
+    let callback = |_: Diagnostic| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble_str("SECTION \"a\", ROM0\nZed:\nA_ EQU 3\nM EQUS \"s\"\n")
+        .unwrap();
+    let mut seen = Vec::new();
+    asm.for_each_symbol(|name, sym| seen.push((name.to_string(), sym.get_value())));
+    assert_eq!(
+        seen,
+        [
+            ("A_".to_string(), Some(3)),
+            ("M".to_string(), None),
+            ("Zed".to_string(), None),
+            ("_RS".to_string(), Some(0)),
+        ]
+    );
+}
+
+#[test]
+fn rs_overflow_is_clamped() {
+    assert_eq!(
+        assemble("A_ rb $7FFF_FFF0\nB_ rl 8\nC_ rw 1\nSECTION \"a\", ROM0\ndl A_, B_, C_, _RS\n"),
+        (
+            [
+                [0, 0, 0, 0],
+                [0xF0, 0xFF, 0xFF, 0x7F],
+                [0xFF, 0xFF, 0xFF, 0x7F],
+                [0xFF, 0xFF, 0xFF, 0x7F],
+            ]
+            .concat(),
+            vec!["W: _RS overflowed, clamped to 2147483647".to_string(); 2]
+        )
+    );
+}
+
+#[test]
+fn redef_replaces_symbols() {
+    assert_eq!(
+        assemble(
+            "REDEF FOO EQUS \"a\"\nREDEF FOO EQUS \"{FOO}b\"\nN EQU 1\nREDEF N EQU N + 1\nSECTION \"a\", ROM0\ndb \"{FOO}\", N\n"
+        ),
+        (b"ab\x02".to_vec(), vec![])
+    );
+    assert_eq!(
+        diagnostics("S SET 2\nREDEF S EQU 3\n"),
+        ["E: Cannot redefine SET symbol \"S\" as EQU at ???:2:1-2:14"]
+    );
+}
+
+#[test]
+fn symbols_carry_over_appended_sources() {
+    let callback = |_: Diagnostic| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble_str("N EQU 3\nSECTION \"a\", ROM0\n db N\n")
+        .unwrap();
+    asm.assemble_append(" db N + 1\n".as_bytes()).unwrap();
+    assert_eq!(asm.error_count(), 0);
+    assert_eq!(*asm.section_bytes("a").unwrap(), [3, 4]);
+}
+
+#[test]
+fn fresh_assemblers_can_append() {
+    let callback = |_: Diagnostic| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.define_cli_symbol("N=2").unwrap();
+    asm.assemble_append("SECTION \"a\", ROM0\n db \"a\", 'b', N\nX RB 1\n".as_bytes())
+        .unwrap();
+    assert_eq!(asm.error_count(), 0);
+    assert_eq!(*asm.section_bytes("a").unwrap(), [0x61, 0x62, 2]);
+    assert_eq!(
+        asm.resolve_symbol("_RS").unwrap().unwrap().get_value(),
+        Some(1)
+    );
+}
+
+#[test]
+fn symbols_can_be_defined_directly() {
+    let callback = |_: Diagnostic| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble_str("SECTION \"a\", ROM0\nGlob:\n").unwrap();
+    asm.def_equ("A".into(), 3.into()).unwrap();
+    let glob = asm.symbol_expr("Glob".into()).unwrap();
+    assert_eq!(
+        asm.def_equ("B".into(), glob).unwrap_err().to_string(),
+        "Expression `Glob` is not constant"
+    );
+    asm.def_set("C".into(), 1.into()).unwrap();
+    asm.def_set("C".into(), 2.into()).unwrap();
+    assert_eq!(
+        asm.def_equ("C".into(), 2.into()).unwrap_err().to_string(),
+        "Cannot redefine SET symbol \"C\" as EQU"
+    );
+    asm.def_equ(".loc".into(), 4.into()).unwrap();
+    asm.def_label("Lbl".into(), false).unwrap();
+
+    let mut seen = Vec::new();
+    asm.for_each_symbol(|name, sym| seen.push((name.to_string(), sym.get_value())));
+    assert_eq!(
+        seen,
+        [
+            ("A".to_string(), Some(3)),
+            ("C".to_string(), Some(2)),
+            ("Glob".to_string(), None),
+            ("Glob.loc".to_string(), Some(4)),
+            ("Lbl".to_string(), None),
+            ("_RS".to_string(), Some(0)),
+        ]
+    );
+}
+
+#[test]
+fn referenced_symbols_cannot_be_purged() {
+    assert_eq!(
+        diagnostics("SECTION \"a\", ROM0\nLabel:\n dw Label\nExp::\nN EQU 1\n purge N\n purge Label\n purge Exp\n purge _RS\n"),
+        [
+            "E: Symbol \"Label\" cannot be purged, as the object file refers to it at ???:7:2-7:13",
+            "E: Symbol \"Exp\" cannot be purged, as the object file refers to it at ???:8:2-8:11",
+            "E: Built-in symbol \"_RS\" cannot be purged at ???:9:2-9:11",
+        ]
+    );
+}
+
+#[test]
+fn redefinitions_say_what_went_wrong() {
+    assert_eq!(
+        diagnostics("S = 1\nS EQU 2\nQ EQU 1\nQ = 2\nSECTION \"a\", ROM0\nLbl:\nLbl:\nX EQUS \"a\"\nX EQUS \"b\"\n"),
+        [
+            "E: Cannot redefine SET symbol \"S\" as EQU at ???:2:1-2:8",
+            "E: Cannot redefine EQU symbol \"Q\" as SET at ???:4:1-4:6",
+            "E: Label \"Lbl\" is already defined at ???:7:1-7:4",
+            "E: Redefined symbol at ???:9:1-9:11",
+        ]
+    );
+}
+
+#[test]
+fn rsset_takes_constant_expressions() {
+    let callback = |_: Diagnostic| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble_str("RSSET 2 + 2\nField RB 1\n").unwrap();
+    assert_eq!(
+        asm.resolve_symbol("Field").unwrap().unwrap().get_value(),
+        Some(4)
+    );
+    assert_eq!(asm.rs_value(), 5);
+    assert_eq!(
+        diagnostics("SECTION \"a\", ROM0\nLbl:\nRSSET Lbl\n"),
+        ["E: Expression `Lbl` is not constant at ???:3:1-3:10"]
+    );
+}
+
+#[test]
+fn symbols_round_trip() {
+    let mut equ = Symbol::new_equ("Foo".to_string(), -42);
+    equ.export();
+    let syms = [
+        equ,
+        Symbol::new_set("Bar".to_string(), 7),
+        Symbol::new_equs("Str".to_string(), "a\0b é".to_string()),
+        Symbol::new_label("Lbl".to_string(), 3, 0x1234),
+        Symbol::new_macro("M".to_string(), "nop\n".to_string()),
+    ];
+    let mut buf = Vec::new();
+    for sym in &syms {
+        sym.write(&mut buf).unwrap();
+    }
+    let mut input = &buf[..];
+    for sym in &syms {
+        let read = Symbol::read(&mut input).unwrap();
+        assert_eq!(format!("{:?}", read), format!("{:?}", sym));
+        assert_eq!(read.is_exported(), sym.is_exported());
+    }
+    assert!(input.is_empty());
+    // Truncated, then with an unknown type
+    assert!(Symbol::read(&[3, 0, 0, 0, b'x'][..]).is_err());
+    assert!(Symbol::read(&[1, 0, 0, 0, b'x', 9][..]).is_err());
+}
+
+#[test]
+fn equs_can_supply_mnemonics() {
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\nfoo EQUS \"ld a,\"\n foo 5\nbar EQUS \"foo\"\n bar 6\nN EQUS \"1 + \"\n db N 2\n"),
+        [0x3e, 5, 0x3e, 6, 3]
+    );
+}
+
+#[test]
+fn label_names_can_be_interpolated() {
+    assert_eq!(
+        labels("SECTION \"a\", ROM0[0]\nx EQUS \"Foo\"\n{x}Bar:\n{x}Bar.loc:\nn = 3\nL{d:n}:\n"),
+        ["00:0000 FooBar", "00:0000 FooBar.loc", "00:0000 L3"]
+    );
+    assert_eq!(
+        diagnostics("SECTION \"a\", ROM0\ny EQUS \"\"\n{y}:\nw EQUS \"a b\"\n{w}:\n"),
+        [
+            "E: Illegal symbol name \"\" at ???:3:1-3:4",
+            "E: Illegal symbol name \"a b\" at ???:5:1-5:4",
+        ]
+    );
+}
+
+#[test]
+fn case_collisions_can_be_reported() {
+    let src = "Foo EQU 1\nfoo EQU 2\nFOO EQU 3\nBar EQU 4\nPURGE Bar\nbar EQU 5\n";
+    assert_eq!(diagnostics(src), Vec::<String>::new());
+    assert_eq!(
+        diagnostics_with(src, |asm| asm.enable_warning(WarningClass::CaseCollision)),
+        [
+            "W: Symbol \"foo\" only differs from \"Foo\" by case",
+            "W: Symbol \"FOO\" only differs from \"Foo\" by case",
+        ]
+    );
+
+    let callback = |_: Diagnostic| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble_str(src).unwrap();
+    assert_eq!(
+        asm.resolve_symbol("Foo").unwrap().unwrap().get_value(),
+        Some(1)
+    );
+    assert_eq!(
+        asm.resolve_symbol("foo").unwrap().unwrap().get_value(),
+        Some(2)
+    );
+}
+
+#[test]
+fn labels_in_fixed_sections_have_values() {
+    let callback = |_: Diagnostic| ControlFlow::Continue(());
+    let asm = Assembler::new(&callback);
+    asm.assemble_str("SECTION \"a\", ROM0[$0000]\nds $10\nLabel:\ndw Label, Label + 1, Float\nSECTION \"b\", ROMX[$4000]\nFar: db Far >> 8\nSECTION \"c\", ROM0\nFloat:\n").unwrap();
+    assert_eq!(
+        asm.section_bytes("a").unwrap()[0x10..],
+        [0x10, 0, 0x11, 0, 0, 0]
+    );
+    assert_eq!(*asm.section_bytes("b").unwrap(), [0x40]);
+    let label = asm.resolve_symbol("Label").unwrap().unwrap();
+    assert_eq!(label.get_label(), Some((0, 0x10)));
+}
+
+#[test]
+fn equs_is_text_not_a_number() {
+    // `FOO` is expanded to the text `5`, which is then read as a number
+    assert_eq!(
+        bytes("SECTION \"a\", ROM0\nFOO EQUS \"5\"\ndb FOO\nBAR EQUS \"\\\"5\\\"\"\ndb BAR\n"),
+        [0x05, 0x35]
+    );
+    assert_eq!(
+        diagnostics("SECTION \"a\", ROM0\ndb FOO\nFOO EQUS \"5\"\n"),
+        ["E: Symbol \"FOO\" was used as a number before being defined as a string at ???:3:1-3:13"]
+    );
+}